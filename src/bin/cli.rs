@@ -1,10 +1,12 @@
 use std::{
     path::{Path, PathBuf},
-    sync::LazyLock,
+    sync::{LazyLock, Mutex},
+    time::Duration,
 };
 
 use clap::{arg, command, Command};
 use easy_fm::prelude::*;
+use indicatif::{ProgressBar, ProgressStyle};
 use serde::{Deserialize, Serialize};
 
 static HOME: LazyLock<PathBuf> =
@@ -13,11 +15,30 @@ static HOME: LazyLock<PathBuf> =
 static DEFAULT_CONFIG_DIR: LazyLock<PathBuf> = LazyLock::new(|| HOME.join(".config/easy-fm"));
 static DEFAULT_CONFIG_PATH: LazyLock<PathBuf> =
     LazyLock::new(|| DEFAULT_CONFIG_DIR.join("config.toml"));
+static DEFAULT_CACHE_DIR: LazyLock<PathBuf> = LazyLock::new(|| HOME.join(".cache/easy-fm"));
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 struct Config {
     pub r#type: String,
     pub config: String,
+    /// The datastore of files put without one
+    #[serde(default)]
+    pub default_ds: Option<String>,
+    /// Rules choosing the datastore of files put without one
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    /// Copies of each file to keep across datastores
+    #[serde(default)]
+    pub replication: Replication,
+    /// Bytes per second all transfers are limited to, e.g. `5M`
+    #[serde(default)]
+    pub limit_rate: Option<String>,
+    /// Size of the download cache, e.g. `10G`, which is off without it
+    #[serde(default)]
+    pub cache_size: Option<String>,
+    /// Directory of the download cache
+    #[serde(default)]
+    pub cache_dir: Option<PathBuf>,
 }
 
 impl Default for Config {
@@ -29,6 +50,12 @@ impl Default for Config {
                 .to_str()
                 .unwrap()
                 .to_string(),
+            default_ds: None,
+            rules: Vec::new(),
+            replication: Replication::default(),
+            limit_rate: None,
+            cache_size: None,
+            cache_dir: None,
         }
     }
 }
@@ -42,29 +69,258 @@ fn load_or_default(path: &Path) -> Config {
     }
 }
 
-fn print_meta(meta: &Vec<MetaRecord>) {
+/// The download cache of the configuration
+fn cache(config: &Config) -> Cache {
+    let size = config
+        .cache_size
+        .as_deref()
+        .map(|x| parse_size(x).expect("Failed to parse cache_size"))
+        .unwrap_or(0);
+    Cache::new(
+        config
+            .cache_dir
+            .clone()
+            .unwrap_or(DEFAULT_CACHE_DIR.to_path_buf()),
+        size,
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Output {
+    Table,
+    Json,
+    Jsonl,
+    Csv,
+}
+
+/// Print rows in the output format, calling `table` to print a table
+fn print_rows<T: Serialize>(output: Output, rows: &[T], table: impl FnOnce(&[T])) {
+    match output {
+        Output::Table => table(rows),
+        Output::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(rows).expect("Failed to serialize")
+        ),
+        Output::Jsonl => {
+            for row in rows {
+                println!(
+                    "{}",
+                    serde_json::to_string(row).expect("Failed to serialize")
+                );
+            }
+        }
+        Output::Csv => print_csv(rows),
+    }
+}
+
+/// Print rows as CSV, with nested values as JSON
+fn print_csv<T: Serialize>(rows: &[T]) {
+    let rows = rows
+        .iter()
+        .map(|x| serde_json::to_value(x).expect("Failed to serialize"))
+        .collect::<Vec<_>>();
+    let mut header: Vec<String> = Vec::new();
+    for key in rows
+        .iter()
+        .filter_map(|x| x.as_object())
+        .flat_map(|x| x.keys())
+    {
+        if !header.contains(key) {
+            header.push(key.clone());
+        }
+    }
+    let field = |s: &str| match s.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", s.replace('"', "\"\"")),
+        false => s.to_string(),
+    };
     println!(
-        "{: <40} {: <10} {: <10} {: <40} {: <10}",
-        "gid", "dsid", "name", "raw", "desc"
+        "{}",
+        header
+            .iter()
+            .map(|x| field(x))
+            .collect::<Vec<_>>()
+            .join(",")
     );
-    for MetaRecord {
-        gid,
-        dsid,
-        name,
-        raw,
-        desc,
-    } in meta
-    {
+    for row in &rows {
+        let fields = header.iter().map(|k| match row.get(k) {
+            None | Some(serde_json::Value::Null) => String::new(),
+            Some(serde_json::Value::String(s)) => field(s),
+            Some(v) => field(&v.to_string()),
+        });
+        println!("{}", fields.collect::<Vec<_>>().join(","));
+    }
+}
+
+fn print_meta(output: Output, meta: &[MetaRecord]) {
+    print_rows(output, meta, |meta| {
         println!(
             "{: <40} {: <10} {: <10} {: <40} {: <10}",
-            gid, dsid, name, raw, desc
+            "gid", "dsid", "name", "raw", "desc"
         );
+        for MetaRecord {
+            gid,
+            dsid,
+            name,
+            raw,
+            desc,
+            ..
+        } in meta
+        {
+            println!(
+                "{: <40} {: <10} {: <10} {: <40} {: <10}",
+                gid, dsid, name, raw, desc
+            );
+        }
+    });
+}
+
+/// Parse a size such as `512`, `10K` or `5M`, in powers of 1024
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (num, shift) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (
+            &s[..i],
+            match c.to_ascii_uppercase() {
+                'K' => 10,
+                'M' => 20,
+                'G' => 30,
+                'T' => 40,
+                _ => return Err(format!("Unknown size suffix: {c}")),
+            },
+        ),
+        _ => (s, 0),
+    };
+    num.parse::<u64>()
+        .map(|x| x << shift)
+        .map_err(|e| format!("Invalid size {s}: {e}"))
+}
+
+/// Parse a duration such as `500ms`, `90s`, `12h` or `7d`
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    if let Some(num) = s.strip_suffix("ms") {
+        return num
+            .parse::<u64>()
+            .map(Duration::from_millis)
+            .map_err(|e| format!("Invalid duration {s}: {e}"));
+    }
+    let (num, unit) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (
+            &s[..i],
+            match c.to_ascii_lowercase() {
+                's' => 1,
+                'm' => 60,
+                'h' => 3600,
+                'd' => 86400,
+                'w' => 604800,
+                _ => return Err(format!("Unknown duration suffix: {c}")),
+            },
+        ),
+        _ => (s, 1),
+    };
+    num.parse::<u64>()
+        .map(|x| Duration::from_secs(x * unit))
+        .map_err(|e| format!("Invalid duration {s}: {e}"))
+}
+
+/// Parse a header given as `Name: value`
+fn parse_header(s: &str) -> Result<(String, String), String> {
+    s.split_once(':')
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .filter(|(name, _)| !name.is_empty())
+        .ok_or(format!("Invalid header {s}, expected Name: value"))
+}
+
+/// Parse the value of a config key set on the command line as JSON, so that
+/// numbers, booleans and lists keep their type, falling back to a string; keys
+/// holding a string keep it, e.g. a numeric password
+fn config_value(current: &serde_json::Value, v: &str) -> serde_json::Value {
+    match current {
+        serde_json::Value::String(_) => serde_json::Value::String(v.to_string()),
+        _ => serde_json::from_str(v).unwrap_or(serde_json::Value::String(v.to_string())),
+    }
+}
+
+fn print_size(size: Option<u64>) -> String {
+    size.map(|x| x.to_string()).unwrap_or("-".to_string())
+}
+
+/// Format a Unix time as UTC, e.g. `2024-09-30 17:05:00`
+fn print_time(time: Option<u64>) -> String {
+    let Some(time) = time else {
+        return "-".to_string();
+    };
+    let (days, secs) = (time / 86400, time % 86400);
+    // the civil date of a day count, counting eras of 400 years from 0000-03-01
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// An observer drawing a progress bar per transfer
+fn progress_bars() -> impl Fn(&Progress) + Send + Sync {
+    let bar: Mutex<Option<(String, ProgressBar)>> = Mutex::new(None);
+    move |progress| {
+        let mut bar = bar.lock().unwrap();
+        if bar
+            .as_ref()
+            .is_none_or(|(name, pb)| name != &progress.name || pb.is_finished())
+        {
+            let pb = match progress.total {
+                Some(total) => ProgressBar::new(total),
+                None => ProgressBar::no_length(),
+            };
+            pb.set_style(
+                ProgressStyle::with_template(
+                    "{msg} [{bar:30}] {bytes}/{total_bytes} {binary_bytes_per_sec} {eta}",
+                )
+                .expect("Failed to parse template")
+                .progress_chars("=> "),
+            );
+            pb.set_message(progress.name.clone());
+            *bar = Some((progress.name.clone(), pb));
+        }
+        let (_, pb) = bar.as_ref().unwrap();
+        pb.set_position(progress.bytes);
+        if progress.total.is_some_and(|x| progress.bytes >= x) {
+            pb.finish();
+        }
     }
 }
 
+/// Print the checks, returning whether all of them passed
+fn print_checks(output: Output, checks: &[Check]) -> bool {
+    print_rows(output, checks, |checks| {
+        println!("{: <10} {: <10} {: <10}", "op", "latency", "result");
+        for Check { op, latency, error } in checks {
+            println!(
+                "{: <10} {: <10} {: <10}",
+                op,
+                format!("{}ms", latency.as_millis()),
+                error.as_deref().unwrap_or("ok")
+            );
+        }
+    });
+    checks.iter().all(|x| x.error.is_none())
+}
+
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::fmt::init();
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
     let cmd = command!()
         .version("0.1")
         .subcommand_required(true)
@@ -86,27 +342,168 @@ async fn main() {
                     Command::new("put")
                         .visible_alias("p")
                         .about("Put a data storage")
+                        .arg(arg!(-n --name [name] "The name of the data storage"))
+                        .arg(arg!(-t --test "Test the data storage before putting it"))
                         .subcommands(&[Command::new("s3").about("Put an S3 data storage").args(&[
                             arg!(<region> "The S3 region"),
                             arg!(<endpoint> "The S3 endpoint"),
                             arg!(<access_key> "The S3 access key"),
                             arg!(<secret_key> "The S3 secret key"),
                             arg!(<bucket> "The S3 bucket"),
-                        ])])
+                        ]),
+                        Command::new("sftp")
+                            .about("Put an SFTP data storage")
+                            .args(&[
+                                arg!(<host> "The SSH host"),
+                                arg!(<user> "The SSH user"),
+                                arg!(<dir> "The directory to store files under"),
+                                arg!(-P --port [port] "The SSH port")
+                                    .default_value("22")
+                                    .value_parser(clap::value_parser!(u16)),
+                                arg!(-k --key [key] "The private key file")
+                                    .value_hint(clap::ValueHint::FilePath)
+                                    .value_parser(clap::value_parser!(PathBuf)),
+                                arg!(-p --password [password] "The password, or the passphrase of the key"),
+                                arg!(-f --fingerprint [fingerprint] "The SHA256 fingerprint of the host key, trusted instead of ~/.ssh/known_hosts"),
+                            ]),
+                        Command::new("webdav")
+                            .about("Put a WebDAV data storage")
+                            .args(&[
+                                arg!(<url> "The URL of the collection to store files under"),
+                                arg!(-u --user [user] "The user of basic authentication"),
+                                arg!(-p --password [password] "The password of basic authentication")
+                                    .requires("user"),
+                            ]),
+                        Command::new("http")
+                            .about("Put a plain HTTP data storage")
+                            .args(&[
+                                arg!(<url> "The URL to fetch files under"),
+                                arg!(-w --writable "Store and delete files with PUT and DELETE too"),
+                                arg!(-H --header [header] "A header to send, as 'Name: value'")
+                                    .action(clap::ArgAction::Append)
+                                    .value_parser(parse_header),
+                                arg!(--token [token] "The token of bearer authentication"),
+                            ]),
+                        Command::new("ec")
+                            .about("Put an erasure-coded data storage over others")
+                            .args(&[
+                                arg!(<data> "The number of data shards")
+                                    .value_parser(clap::value_parser!(usize)),
+                                arg!(<parity> "The number of parity shards")
+                                    .value_parser(clap::value_parser!(usize)),
+                                arg!(<datastore_id> ... "The datastore ID of each shard, data shards first"),
+                            ]),
+                        Command::new("chunk")
+                            .about("Put a chunked data storage over another, sharing chunks across files")
+                            .args(&[
+                                arg!(-a --avg_size [avg_size] "The average chunk size in bytes")
+                                    .default_value("1048576")
+                                    .value_parser(clap::value_parser!(u32)),
+                                arg!(<datastore_id> "The datastore ID holding the chunks"),
+                            ])])
                         .subcommand_required(true),
                     Command::new("del")
                         .visible_alias("d")
                         .about("Delete a data storage")
+                        .args(&[
+                            arg!(--cascade "Delete the files of the data storage too"),
+                            arg!(--"migrate-to" <to> "Move the files to another data storage first")
+                                .required(false),
+                            arg!(--forget "Forget the files, leaving the objects in place"),
+                            arg!(<datastore_id> "The datastore ID"),
+                        ])
+                        .group(clap::ArgGroup::new("policy").args(["cascade", "migrate-to", "forget"])),
+                    Command::new("test")
+                        .visible_alias("t")
+                        .about("Test the connectivity of a data storage")
                         .args(&[arg!(<datastore_id> "The datastore ID")]),
+                    Command::new("update")
+                        .visible_alias("u")
+                        .about("Update a data storage")
+                        .args(&[
+                            arg!(-n --name [name] "The new name of the data storage"),
+                            arg!(-s --set [set] "Set a config value, as key=value with the value in JSON unless the key holds a string")
+                                .action(clap::ArgAction::Append),
+                            arg!(<datastore_id> "The datastore ID"),
+                        ])
+                        .group(
+                            clap::ArgGroup::new("update")
+                                .args(["name", "set"])
+                                .multiple(true)
+                                .required(true),
+                        ),
+                    Command::new("migrate")
+                        .about("Move all files of a data storage to another")
+                        .args(&[
+                            arg!(<from> "The source datastore ID"),
+                            arg!(<to> "The target datastore ID"),
+                        ]),
+                    Command::new("import")
+                        .about("Import existing objects of a data storage")
+                        .args(&[
+                            arg!(-p --prefix [prefix] "Only import objects with this prefix"),
+                            arg!(<datastore_id> "The datastore ID"),
+                            arg!([name] ... "Import these objects instead of listing them")
+                                .conflicts_with("prefix"),
+                        ]),
+                    Command::new("quota")
+                        .about("Set the quotas of a data storage, removing those omitted")
+                        .args(&[
+                            arg!(-s --soft [soft] "Warn about puts above this size, e.g. 10G")
+                                .value_parser(parse_size),
+                            arg!(--hard [hard] "Reject puts above this size, e.g. 20G")
+                                .value_parser(parse_size),
+                            arg!(<datastore_id> "The datastore ID"),
+                        ]),
+                    Command::new("stats")
+                        .about("Show the usage and quotas of data storages"),
+                    Command::new("limit")
+                        .about("Limit the bandwidth of a data storage, removing the limit if omitted")
+                        .args(&[
+                            arg!(-r --rate [rate] "Bytes per second, e.g. 5M")
+                                .value_parser(parse_size),
+                            arg!(<datastore_id> "The datastore ID"),
+                        ]),
+                    Command::new("retry")
+                        .about("Set the retry policy of a data storage, defaulting the options omitted")
+                        .args(&[
+                            arg!(-b --backoff <backoff> "Delay before the first retry, e.g. 500ms")
+                                .required(false)
+                                .value_parser(parse_duration),
+                            arg!(--"max-backoff" <max_backoff> "Upper bound of the delay between tries")
+                                .required(false)
+                                .value_parser(parse_duration),
+                            arg!(<datastore_id> "The datastore ID"),
+                        ]),
+                    Command::new("lifecycle")
+                        .about("Set the lifecycle rules of a data storage, removing those omitted")
+                        .args(&[
+                            arg!(--"delete-after" <days> "Delete files put this many days ago")
+                                .required(false)
+                                .value_parser(clap::value_parser!(u64)),
+                            arg!(--"move-after" <days> "Move files put this many days ago")
+                                .required(false)
+                                .requires("to")
+                                .value_parser(clap::value_parser!(u64)),
+                            arg!(--to <to> "The datastore ID to move files to")
+                                .required(false)
+                                .requires("move-after"),
+                            arg!(<datastore_id> "The datastore ID"),
+                        ]),
                 ])
                 .arg_required_else_help(true)
                 .subcommand_required(true),
             Command::new("put")
                 .visible_alias("p")
                 .about("Put something")
+                .allow_missing_positional(true)
                 .args(&[
-                    arg!(-r --raw [raw] "The raw data").value_parser(["gid", "gide"]),
-                    arg!(<datastore_id> "The datastore ID"),
+                    arg!(-r --raw [raw] "The raw data").value_parser(["gid", "gide", "hash"]),
+                    arg!(-t --tag [tag] "Tag the file for datastore selection")
+                        .action(clap::ArgAction::Append),
+                    arg!(-e --expire [expire] "Delete the file after this long, e.g. 7d")
+                        .value_parser(parse_duration),
+                    arg!([datastore_id] "The datastore ID, chosen by the rules if omitted"),
                     arg!(<path> "The path to the file")
                         .value_hint(clap::ValueHint::AnyPath)
                         .value_parser(clap::value_parser!(std::path::PathBuf)),
@@ -146,6 +543,77 @@ async fn main() {
                 .visible_alias("l")
                 .about("List files")
                 .arg(arg!(-i [datastore_id]"The datastore ID")),
+            Command::new("du")
+                .about("Show the disk usage of files")
+                .arg(
+                    arg!(-b --by [by] "Group by")
+                        .value_parser(["datastore", "tag", "folder"])
+                        .default_value("datastore"),
+                ),
+            Command::new("sync")
+                .about("Mirror a local directory with a datastore")
+                .args(&[
+                    arg!(-m --mode [mode] "The direction to sync in")
+                        .value_parser(["push", "pull", "both"])
+                        .default_value("push"),
+                    arg!(--delete "Delete files missing from the source side"),
+                    arg!(-n --"dry-run" "Only show what would be done"),
+                    arg!(<local_dir> "The local directory")
+                        .value_hint(clap::ValueHint::DirPath)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                    arg!(<target> "The datastore ID, optionally followed by :prefix"),
+                ]),
+            Command::new("watch")
+                .about("Upload the files created or modified in a directory")
+                .args(&[
+                    arg!(-s --settle [settle] "How long a file must go without writes")
+                        .default_value("2s")
+                        .value_parser(parse_duration),
+                    arg!(-r --retries [retries] "Attempts after a failed upload")
+                        .default_value("3")
+                        .value_parser(clap::value_parser!(u32)),
+                    arg!(-t --tag [tag] "Tag the uploaded files")
+                        .action(clap::ArgAction::Append),
+                    arg!(--delete "Delete the local file after upload"),
+                    arg!(--"move-to" <to> "Move the local file into this directory after upload")
+                        .required(false)
+                        .conflicts_with("delete")
+                        .value_hint(clap::ValueHint::DirPath)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                    arg!(<dir> "The directory to watch")
+                        .value_hint(clap::ValueHint::DirPath)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                    arg!(<datastore_id> "The datastore ID"),
+                ]),
+            Command::new("lifecycle")
+                .about("File expiration and lifecycle rules")
+                .subcommand(
+                    Command::new("run")
+                        .about("Delete expired files and apply the lifecycle rules"),
+                )
+                .arg_required_else_help(true)
+                .subcommand_required(true),
+            Command::new("cache")
+                .about("Download cache commands")
+                .subcommands(&[
+                    Command::new("list")
+                        .about("List cached files, most recently used first")
+                        .visible_alias("ls"),
+                    Command::new("clear").about("Remove every cached file"),
+                    Command::new("stats").about("Show the size and hit rate of the cache"),
+                ])
+                .arg_required_else_help(true)
+                .subcommand_required(true),
+            Command::new("repair")
+                .about("Re-create missing replicas of files"),
+            Command::new("fsck")
+                .about("Check a datastore against the metadata")
+                .args(&[
+                    arg!(--import "Create records for objects without one"),
+                    arg!(--delete "Delete objects without a record").conflicts_with("import"),
+                    arg!(--forget "Delete records whose object vanished"),
+                    arg!(<datastore_id> "The datastore ID"),
+                ]),
         ])
         .arg(
             arg!(-c [config] "The configuration file")
@@ -153,7 +621,38 @@ async fn main() {
                 .value_hint(clap::ValueHint::FilePath)
                 .value_parser(clap::value_parser!(PathBuf)),
         )
+        .arg(
+            arg!(-o --output [output] "The output format")
+                .value_parser(["table", "json", "jsonl", "csv"])
+                .default_value("table")
+                .global(true),
+        )
+        .arg(arg!(-q --quiet "Do not show progress bars").global(true))
+        .arg(
+            arg!(--"limit-rate" <rate> "Bytes per second all transfers are limited to, e.g. 5M")
+                .required(false)
+                .value_parser(parse_size)
+                .global(true),
+        )
+        .arg(
+            arg!(--attempts <attempts> "Tries of each datastore operation, the first one included")
+                .required(false)
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .global(true),
+        )
+        .arg(
+            arg!(--timeout <timeout> "Time limit of each datastore operation without progress, e.g. 30s")
+                .required(false)
+                .value_parser(parse_duration)
+                .global(true),
+        )
         .get_matches();
+    let output = match cmd.get_one::<String>("output").unwrap().as_str() {
+        "json" => Output::Json,
+        "jsonl" => Output::Jsonl,
+        "csv" => Output::Csv,
+        _ => Output::Table,
+    };
     let config = load_or_default(
         cmd.get_one::<PathBuf>("config")
             .expect("Failed to get config"),
@@ -173,20 +672,94 @@ async fn main() {
         return;
     }
 
+    if let Some(("cache", cmd)) = cmd.subcommand() {
+        let cache = cache(&config);
+        match cmd.subcommand() {
+            Some(("list", _)) => print_rows(output, &cache.ls(), |entries| {
+                println!("{: <40} {: <15} {: <20}", "gid", "size", "accessed");
+                for CacheEntry {
+                    gid,
+                    size,
+                    accessed,
+                    ..
+                } in entries
+                {
+                    println!("{: <40} {: <15} {: <20}", gid, size, print_time(*accessed));
+                }
+            }),
+            Some(("clear", _)) => cache.clear().expect("Failed to clear cache"),
+            Some(("stats", _)) => print_rows(output, &[cache.stats()], |stats| {
+                let CacheStats {
+                    entries,
+                    size,
+                    max_size,
+                    hits,
+                    misses,
+                } = &stats[0];
+                println!("entries: {entries}, size: {size} of {max_size}");
+                println!("hits: {hits}, misses: {misses}");
+            }),
+            _ => {}
+        }
+        return;
+    }
+
     let mut rm = RM::new(&config.r#type, &config.config);
+    if config.cache_size.is_some() {
+        rm.set_cache(cache(&config));
+    }
+    rm.set_policy(Rules::new(config.rules, config.default_ds));
+    rm.set_replication(config.replication);
+    if !cmd.get_flag("quiet") {
+        rm.set_observer(progress_bars());
+    }
+    let limit_rate = match cmd.get_one::<u64>("limit-rate") {
+        Some(rate) => Some(*rate),
+        None => config
+            .limit_rate
+            .as_deref()
+            .map(|x| parse_size(x).expect("Failed to parse limit_rate")),
+    };
+    rm.set_limit_rate(limit_rate);
+    let attempts = cmd.get_one::<u32>("attempts");
+    let timeout = cmd.get_one::<Duration>("timeout");
+    if attempts.is_some() || timeout.is_some() {
+        let default = RetryPolicy::default();
+        rm.set_retry(RetryPolicy {
+            attempts: attempts.copied().unwrap_or(default.attempts),
+            timeout_ms: timeout.map(|x| x.as_millis() as u64),
+            ..default
+        });
+    }
     match cmd.subcommand() {
         Some(("ds", ds)) => match ds.subcommand() {
-            Some(("list", _)) => {
-                println!("{: <10} {: <10} {: <10}", "id", "type", "config");
-                for DataStorageRecord { id, r#type, cfg } in rm.ds_ls().await {
-                    println!("{: <10} {: <10} {: <10}", id, r#type, cfg);
+            Some(("list", _)) => print_rows(output, &rm.ds_ls().await, |records| {
+                println!(
+                    "{: <10} {: <10} {: <10} {: <10}",
+                    "id", "name", "type", "config"
+                );
+                for DataStorageRecord {
+                    id,
+                    name,
+                    r#type,
+                    cfg,
+                    ..
+                } in records
+                {
+                    println!(
+                        "{: <10} {: <10} {: <10} {: <10}",
+                        id,
+                        name.as_deref().unwrap_or_default(),
+                        r#type,
+                        cfg
+                    );
                 }
-            }
+            }),
             Some(("put", put)) => {
-                if let Some(("s3", s3)) = put.subcommand() {
-                    rm.ds_put(
+                let (r#type, cfg) = match put.subcommand() {
+                    Some(("s3", s3)) => (
                         "s3",
-                        &serde_json::to_string(&S3config {
+                        serde_json::to_string(&S3config {
                             region: s3.get_one::<String>("region").cloned().unwrap(),
                             endpoint: s3.get_one::<String>("endpoint").cloned().unwrap(),
                             access_key: s3.get_one::<String>("access_key").cloned().unwrap(),
@@ -194,30 +767,269 @@ async fn main() {
                             bucket: s3.get_one::<String>("bucket").cloned().unwrap(),
                         })
                         .expect("Failed to serialize"),
-                    )
-                    .await;
+                    ),
+                    Some(("sftp", sftp)) => (
+                        "sftp",
+                        serde_json::to_string(&SftpConfig {
+                            host: sftp.get_one::<String>("host").cloned().unwrap(),
+                            port: *sftp.get_one::<u16>("port").unwrap(),
+                            user: sftp.get_one::<String>("user").cloned().unwrap(),
+                            key: sftp.get_one::<PathBuf>("key").cloned(),
+                            password: sftp.get_one::<String>("password").cloned(),
+                            dir: sftp.get_one::<String>("dir").cloned().unwrap(),
+                            fingerprint: sftp.get_one::<String>("fingerprint").cloned(),
+                        })
+                        .expect("Failed to serialize"),
+                    ),
+                    Some(("webdav", webdav)) => (
+                        "webdav",
+                        serde_json::to_string(&WebdavConfig {
+                            url: webdav.get_one::<String>("url").cloned().unwrap(),
+                            user: webdav.get_one::<String>("user").cloned(),
+                            password: webdav.get_one::<String>("password").cloned(),
+                        })
+                        .expect("Failed to serialize"),
+                    ),
+                    Some(("http", http)) => (
+                        "http",
+                        serde_json::to_string(&HttpConfig {
+                            url: http.get_one::<String>("url").cloned().unwrap(),
+                            writable: http.get_flag("writable"),
+                            headers: http
+                                .get_many::<(String, String)>("header")
+                                .unwrap_or_default()
+                                .cloned()
+                                .collect(),
+                            token: http.get_one::<String>("token").cloned(),
+                        })
+                        .expect("Failed to serialize"),
+                    ),
+                    Some(("ec", ec)) => (
+                        "ec",
+                        serde_json::to_string(&ErasureConfig {
+                            data: *ec.get_one::<usize>("data").unwrap(),
+                            parity: *ec.get_one::<usize>("parity").unwrap(),
+                            ds: ec
+                                .get_many::<String>("datastore_id")
+                                .unwrap()
+                                .cloned()
+                                .collect(),
+                        })
+                        .expect("Failed to serialize"),
+                    ),
+                    Some(("chunk", chunk)) => (
+                        "chunk",
+                        serde_json::to_string(&ChunkConfig {
+                            ds: chunk.get_one::<String>("datastore_id").cloned().unwrap(),
+                            avg_size: *chunk.get_one::<u32>("avg_size").unwrap(),
+                        })
+                        .expect("Failed to serialize"),
+                    ),
+                    _ => unreachable!(),
+                };
+                if put.get_flag("test") {
+                    let checks = rm.ds_check(r#type, &cfg).await.expect("Failed to check");
+                    if !print_checks(output, &checks) {
+                        eprintln!("Data storage check failed, not putting it");
+                        std::process::exit(1);
+                    }
+                }
+                rm.ds_put(
+                    r#type,
+                    put.get_one::<String>("name").map(|x| x.as_str()),
+                    &cfg,
+                )
+                .await
+                .expect("Failed to put");
+            }
+            Some(("test", test)) => {
+                let checks = rm
+                    .ds_test(test.get_one::<String>("datastore_id").unwrap())
+                    .await
+                    .expect("Failed to test");
+                if !print_checks(output, &checks) {
+                    std::process::exit(1);
                 }
             }
+            Some(("update", update)) => {
+                let dsid = update.get_one::<String>("datastore_id").unwrap();
+                let cfg = match update.get_many::<String>("set") {
+                    Some(set) => {
+                        let record = rm
+                            .ds_ls()
+                            .await
+                            .into_iter()
+                            .find(|x| &x.id == dsid || x.name.as_ref() == Some(dsid))
+                            .expect("Datastore not found");
+                        let mut cfg: serde_json::Map<String, serde_json::Value> =
+                            serde_json::from_str(&record.cfg).expect("Failed to deserialize");
+                        for kv in set {
+                            let (k, v) = kv.split_once('=').expect("Expected key=value");
+                            if !cfg.contains_key(k) {
+                                panic!("Unknown config key: {k}");
+                            }
+                            let value = config_value(&cfg[k], v);
+                            cfg.insert(k.to_string(), value);
+                        }
+                        Some(serde_json::to_string(&cfg).expect("Failed to serialize"))
+                    }
+                    None => None,
+                };
+                rm.ds_update(
+                    dsid,
+                    update.get_one::<String>("name").map(|x| x.as_str()),
+                    cfg.as_deref(),
+                )
+                .await
+                .expect("Failed to update");
+            }
             Some(("del", del)) => {
-                rm.ds_del(del.get_one::<String>("datastore_id").unwrap())
-                    .await;
+                let policy = if del.get_flag("cascade") {
+                    DeletePolicy::Cascade
+                } else if let Some(to) = del.get_one::<String>("migrate-to") {
+                    DeletePolicy::Migrate(to.clone())
+                } else if del.get_flag("forget") {
+                    DeletePolicy::Forget
+                } else {
+                    DeletePolicy::Refuse
+                };
+                rm.ds_del(del.get_one::<String>("datastore_id").unwrap(), policy)
+                    .await
+                    .expect("Failed to delete");
+            }
+            Some(("migrate", migrate)) => {
+                let mrv = rm
+                    .migrate(
+                        migrate.get_one::<String>("from").unwrap(),
+                        migrate.get_one::<String>("to").unwrap(),
+                    )
+                    .await
+                    .expect("Failed to migrate");
+                print_meta(output, &mrv);
+            }
+            Some(("import", import)) => {
+                let dsid = import.get_one::<String>("datastore_id").unwrap();
+                let mrv = match import.get_many::<String>("name") {
+                    Some(names) => rm.track(dsid, &names.cloned().collect::<Vec<_>>()).await,
+                    None => {
+                        rm.import(dsid, import.get_one::<String>("prefix").map(|x| x.as_str()))
+                            .await
+                    }
+                }
+                .expect("Failed to import");
+                print_meta(output, &mrv);
+            }
+            Some(("quota", quota)) => {
+                rm.ds_quota(
+                    quota.get_one::<String>("datastore_id").unwrap(),
+                    quota.get_one::<u64>("soft").copied(),
+                    quota.get_one::<u64>("hard").copied(),
+                )
+                .await
+                .expect("Failed to set quota");
+            }
+            Some(("limit", limit)) => {
+                rm.ds_limit_rate(
+                    limit.get_one::<String>("datastore_id").unwrap(),
+                    limit.get_one::<u64>("rate").copied(),
+                )
+                .await
+                .expect("Failed to set limit");
+            }
+            Some(("retry", retry)) => {
+                let default = RetryPolicy::default();
+                let policy = RetryPolicy {
+                    attempts: retry
+                        .get_one::<u32>("attempts")
+                        .copied()
+                        .unwrap_or(default.attempts),
+                    backoff_ms: retry
+                        .get_one::<Duration>("backoff")
+                        .map_or(default.backoff_ms, |x| x.as_millis() as u64),
+                    max_backoff_ms: retry
+                        .get_one::<Duration>("max-backoff")
+                        .map_or(default.max_backoff_ms, |x| x.as_millis() as u64),
+                    timeout_ms: retry
+                        .get_one::<Duration>("timeout")
+                        .map(|x| x.as_millis() as u64),
+                };
+                rm.ds_retry(
+                    retry.get_one::<String>("datastore_id").unwrap(),
+                    (policy != default).then_some(&policy),
+                )
+                .await
+                .expect("Failed to set retry policy");
+            }
+            Some(("stats", _)) => print_rows(output, &rm.ds_stats().await, |stats| {
+                println!(
+                    "{: <10} {: <10} {: <10} {: <15} {: <15} {: <15}",
+                    "id", "name", "files", "used", "soft", "hard"
+                );
+                for DsStats {
+                    id,
+                    name,
+                    files,
+                    used,
+                    soft_quota,
+                    hard_quota,
+                } in stats
+                {
+                    println!(
+                        "{: <10} {: <10} {: <10} {: <15} {: <15} {: <15}",
+                        id,
+                        name.as_deref().unwrap_or_default(),
+                        files,
+                        used,
+                        print_size(*soft_quota),
+                        print_size(*hard_quota)
+                    );
+                }
+            }),
+            Some(("lifecycle", lifecycle)) => {
+                let mut rules = Vec::new();
+                if let Some(after_days) = lifecycle.get_one::<u64>("delete-after") {
+                    rules.push(Lifecycle::Delete {
+                        after_days: *after_days,
+                    });
+                }
+                if let Some(after_days) = lifecycle.get_one::<u64>("move-after") {
+                    rules.push(Lifecycle::Move {
+                        after_days: *after_days,
+                        ds: lifecycle.get_one::<String>("to").cloned().unwrap(),
+                    });
+                }
+                rm.ds_lifecycle(lifecycle.get_one::<String>("datastore_id").unwrap(), &rules)
+                    .await
+                    .expect("Failed to set lifecycle");
             }
             _ => {}
         },
         Some(("put", put)) => {
-            let datastore_id = put.get_one::<String>("datastore_id").unwrap();
-            let path = put.get_one::<std::path::PathBuf>("path").unwrap();
+            let tags = put
+                .get_many::<String>("tag")
+                .map(|x| x.cloned().collect::<Vec<_>>())
+                .unwrap_or_default();
             let info = rm
                 .put(
-                    datastore_id,
-                    path,
+                    put.get_one::<String>("datastore_id").map(|x| x.as_str()),
+                    put.get_one::<std::path::PathBuf>("path").unwrap(),
                     put.get_one::<String>("raw")
                         .map(|x| x.as_str())
                         .unwrap_or("raw"),
+                    &tags,
+                    put.get_one::<Duration>("expire").copied(),
                 )
-                .await
-                .unwrap();
-            println!("name: {}, discription: {}", info.name, info.desc);
+                .await;
+            let info = match info {
+                Ok(info) => info,
+                Err(e) => {
+                    eprintln!("Failed to put: {e:#}");
+                    std::process::exit(1);
+                }
+            };
+            print_rows(output, &[info], |info| {
+                println!("name: {}, discription: {}", info[0].name, info[0].desc);
+            });
         }
         Some(("get", get)) => {
             let mrv = rm
@@ -228,14 +1040,14 @@ async fn main() {
                 )
                 .await;
             if mrv.is_empty() {
-                println!("No such file");
-            } else if mrv.len() > 1 {
-                print_meta(&mrv);
+                eprintln!("No such file");
+            } else if mrv.iter().any(|x| x.gid != mrv[0].gid) {
+                print_meta(output, &mrv);
             } else {
                 let info = &mrv[0];
                 rm.get(
                     Some(&info.gid),
-                    Some(&info.dsid),
+                    get.get_one::<String>("datastore_id").map(|x| x.as_str()),
                     Some(&info.name),
                     get.get_one::<std::path::PathBuf>("path")
                         .map(|x| x.as_path()),
@@ -253,17 +1065,228 @@ async fn main() {
                 )
                 .await;
             if mrv.is_empty() {
-                println!("No such file");
-            } else if mrv.len() > 1 {
-                print_meta(&mrv);
+                eprintln!("No such file");
+            } else if mrv.iter().any(|x| x.gid != mrv[0].gid) {
+                print_meta(output, &mrv);
             } else {
                 rm.del(&mrv[0].gid).await.expect("Failed to delete");
             }
         }
         Some(("list", list)) => {
             let datastore_id = list.get_one::<String>("datastore_id");
-            print_meta(&rm.ls(None, datastore_id.map(|x| x.as_str()), None).await);
+            print_meta(
+                output,
+                &rm.ls(None, datastore_id.map(|x| x.as_str()), None).await,
+            );
+        }
+        Some(("du", du)) => {
+            let by = match du.get_one::<String>("by").unwrap().as_str() {
+                "tag" => GroupBy::Tag,
+                "folder" => GroupBy::Folder,
+                _ => GroupBy::Datastore,
+            };
+            print_rows(output, &rm.du(by).await, |usage| {
+                println!("{: <40} {: <10} {: <15}", "key", "files", "size");
+                for Usage { key, files, size } in usage {
+                    println!("{: <40} {: <10} {: <15}", key, files, size);
+                }
+            });
+        }
+        Some(("sync", sync)) => {
+            let mode = match sync.get_one::<String>("mode").unwrap().as_str() {
+                "pull" => SyncMode::Pull,
+                "both" => SyncMode::Both,
+                _ => SyncMode::Push,
+            };
+            let target = sync.get_one::<String>("target").unwrap();
+            let (dsid, prefix) = match target.split_once(':') {
+                Some((dsid, prefix)) if !prefix.is_empty() && !prefix.ends_with('/') => {
+                    (dsid, prefix.to_string() + "/")
+                }
+                Some((dsid, prefix)) => (dsid, prefix.to_string()),
+                None => (target.as_str(), String::new()),
+            };
+            let actions = rm
+                .sync(
+                    sync.get_one::<PathBuf>("local_dir").unwrap(),
+                    dsid,
+                    &prefix,
+                    mode,
+                    sync.get_flag("delete"),
+                    sync.get_flag("dry-run"),
+                )
+                .await
+                .expect("Failed to sync");
+            print_rows(output, &actions, |actions| {
+                for action in actions {
+                    let (op, key) = match action {
+                        SyncAction::Upload(key) => ("upload", key),
+                        SyncAction::Download(key) => ("download", key),
+                        SyncAction::DeleteRemote(key) => ("delete remote", key),
+                        SyncAction::DeleteLocal(key) => ("delete local", key),
+                    };
+                    println!("{: <15} {}", op, key);
+                }
+            });
+        }
+        Some(("watch", watch)) => {
+            let after = if watch.get_flag("delete") {
+                AfterUpload::Delete
+            } else if let Some(to) = watch.get_one::<PathBuf>("move-to") {
+                AfterUpload::Move(to.clone())
+            } else {
+                AfterUpload::Keep
+            };
+            let config = WatchConfig {
+                settle: *watch.get_one::<Duration>("settle").unwrap(),
+                retries: *watch.get_one::<u32>("retries").unwrap(),
+                after,
+                tags: watch
+                    .get_many::<String>("tag")
+                    .map(|x| x.cloned().collect())
+                    .unwrap_or_default(),
+            };
+            rm.watch(
+                watch.get_one::<PathBuf>("dir").unwrap(),
+                watch.get_one::<String>("datastore_id").unwrap(),
+                &config,
+            )
+            .await
+            .expect("Failed to watch");
+        }
+        Some(("lifecycle", lifecycle)) => {
+            if let Some(("run", _)) = lifecycle.subcommand() {
+                let report = rm.run_lifecycle().await.expect("Failed to run lifecycle");
+                let failed = !report.errors.is_empty();
+                print_rows(output, &[report], |report| {
+                    let LifecycleReport {
+                        expired,
+                        deleted,
+                        moved,
+                        errors,
+                    } = &report[0];
+                    println!("expired:");
+                    print_meta(Output::Table, expired);
+                    println!("deleted:");
+                    print_meta(Output::Table, deleted);
+                    println!("moved:");
+                    print_meta(Output::Table, moved);
+                    println!("errors:");
+                    for e in errors {
+                        println!("{e}");
+                    }
+                });
+                if failed {
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(("repair", _)) => {
+            let report = rm.repair().await.expect("Failed to repair");
+            let failed =
+                !(report.failed.is_empty() && report.unlisted.is_empty() && report.lost.is_empty());
+            print_rows(output, &[report], |report| {
+                let RepairReport {
+                    repaired,
+                    failed,
+                    unlisted,
+                    lost,
+                } = &report[0];
+                println!("repaired:");
+                print_meta(Output::Table, repaired);
+                println!("failed:");
+                print_meta(Output::Table, failed);
+                println!("{: <10} {: <40}", "unlisted", "datastore");
+                for dsid in unlisted {
+                    println!("{: <10} {: <40}", "", dsid);
+                }
+                println!("{: <10} {: <40}", "lost", "gid");
+                for gid in lost {
+                    println!("{: <10} {: <40}", "", gid);
+                }
+            });
+            if failed {
+                std::process::exit(1);
+            }
+        }
+        Some(("fsck", fsck)) => {
+            let repair = [
+                ("import", Repair::Import),
+                ("delete", Repair::Delete),
+                ("forget", Repair::Forget),
+            ]
+            .into_iter()
+            .filter(|(k, _)| fsck.get_flag(k))
+            .map(|(_, v)| v)
+            .collect::<Vec<_>>();
+            let report = rm
+                .fsck(fsck.get_one::<String>("datastore_id").unwrap(), &repair)
+                .await
+                .expect("Failed to fsck");
+            print_rows(output, &[report], |report| {
+                let FsckReport { orphans, missing } = &report[0];
+                println!("{: <10} {: <40}", "orphan", "raw");
+                for raw in orphans {
+                    println!("{: <10} {: <40}", "", raw);
+                }
+                println!("missing:");
+                print_meta(Output::Table, missing);
+            });
         }
         _ => {}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_duration(" 7d "), Ok(Duration::from_secs(7 * 86400)));
+        assert_eq!(parse_duration("30"), Ok(Duration::from_secs(30)));
+        assert!(parse_duration("5y").is_err());
+        assert!(parse_duration("ms").is_err());
+    }
+
+    #[test]
+    fn headers() {
+        let header = |name: &str, value: &str| Ok((name.to_string(), value.to_string()));
+        assert_eq!(parse_header("X-Team: ops"), header("X-Team", "ops"));
+        assert_eq!(
+            parse_header(" Accept :text/plain "),
+            header("Accept", "text/plain")
+        );
+        // only the first colon separates the name
+        assert_eq!(parse_header("X-At: 12:00"), header("X-At", "12:00"));
+        assert_eq!(parse_header("X-Empty:"), header("X-Empty", ""));
+        assert!(parse_header("X-Team ops").is_err());
+        assert!(parse_header(": ops").is_err());
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(print_time(None), "-");
+        assert_eq!(print_time(Some(0)), "1970-01-01 00:00:00");
+        assert_eq!(print_time(Some(951782400)), "2000-02-29 00:00:00");
+        assert_eq!(print_time(Some(1727715900)), "2024-09-30 17:05:00");
+        assert_eq!(print_time(Some(4107542399)), "2100-02-28 23:59:59");
+    }
+
+    #[test]
+    fn config_values() {
+        use serde_json::{json, Value};
+        assert_eq!(config_value(&json!(1048576), "4096"), json!(4096));
+        assert_eq!(config_value(&json!(false), "true"), json!(true));
+        assert_eq!(
+            config_value(&json!(["a"]), r#"["a","b"]"#),
+            json!(["a", "b"])
+        );
+        assert_eq!(config_value(&Value::Null, "secret"), json!("secret"));
+        assert_eq!(config_value(&Value::Null, "22"), json!(22));
+        assert_eq!(config_value(&json!("pass"), "1234"), json!("1234"));
+    }
+}
@@ -6,4 +6,10 @@ pub enum Error {
     NotFound(String),
     #[error("Failed to deal with file: {0}")]
     FileError(String),
+    #[error("Record still in use: {0}")]
+    InUse(String),
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+    #[error("Unsupported by the data storage: {0}")]
+    Unsupported(String),
 }
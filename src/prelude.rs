@@ -1,8 +1,41 @@
 pub use super::error::Error;
 pub use super::rm::build;
 pub use super::rm::init;
+pub use super::rm::AfterUpload;
+pub use super::rm::Cache;
+pub use super::rm::CacheEntry;
+pub use super::rm::CacheStats;
+pub use super::rm::Check;
+pub use super::rm::ChunkConfig;
 pub use super::rm::DataStorage;
 pub use super::rm::DataStorageRecord;
+pub use super::rm::DeletePolicy;
+pub use super::rm::DsStats;
+pub use super::rm::ErasureConfig;
+pub use super::rm::FileInfo;
+pub use super::rm::FsckReport;
+pub use super::rm::GetRequest;
+pub use super::rm::GroupBy;
+pub use super::rm::HttpConfig;
+pub use super::rm::Lifecycle;
+pub use super::rm::LifecycleReport;
 pub use super::rm::MetaRecord;
+pub use super::rm::Meter;
+pub use super::rm::Observer;
+pub use super::rm::Policy;
+pub use super::rm::Progress;
+pub use super::rm::PutRequest;
+pub use super::rm::Repair;
+pub use super::rm::RepairReport;
+pub use super::rm::Replication;
+pub use super::rm::RetryPolicy;
+pub use super::rm::Rule;
+pub use super::rm::Rules;
 pub use super::rm::S3config;
+pub use super::rm::SftpConfig;
+pub use super::rm::SyncAction;
+pub use super::rm::SyncMode;
+pub use super::rm::Usage;
+pub use super::rm::WatchConfig;
+pub use super::rm::WebdavConfig;
 pub use super::rm::RM;
@@ -1,14 +1,180 @@
+mod cache;
 mod ds;
 mod meta;
+mod policy;
+mod sync;
+mod watch;
 
+use crate::error::Error;
 use anyhow::{Context, Result};
+use futures::StreamExt;
 pub use meta::{DataStorageRecord, MetaRecord};
-use std::path::Path;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-pub use ds::{build, DataStorage, S3config};
+pub use cache::{Cache, CacheEntry, CacheStats};
+pub use ds::{
+    build, Check, ChunkConfig, DataStorage, ErasureConfig, HttpConfig, Meter, Observer, Progress,
+    RetryPolicy, S3config, SftpConfig, WebdavConfig,
+};
+pub use policy::{FileInfo, Lifecycle, Policy, Replication, Rule, Rules};
+pub use sync::{SyncAction, SyncMode};
+pub use watch::{AfterUpload, WatchConfig};
 
+/// The resource manager, cheap to clone and share between tasks
+#[derive(Clone)]
 pub struct RM {
-    meta: Box<dyn meta::Meta>,
+    meta: Arc<dyn meta::Meta>,
+    policy: Arc<dyn Policy>,
+    replication: Replication,
+    observer: Arc<Observer>,
+    retry: Option<RetryPolicy>,
+    limit: Option<Arc<ds::Limiter>>,
+    /// Limiters of the datastores with a rate, shared by all transfers of one
+    limiters: Arc<std::sync::Mutex<HashMap<String, Arc<ds::Limiter>>>>,
+    cache: Option<Arc<Cache>>,
+}
+
+/// How to repair a discrepancy found by [`RM::fsck`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Repair {
+    /// Create records for objects that have none
+    Import,
+    /// Delete objects that have no record
+    Delete,
+    /// Delete records whose object vanished
+    Forget,
+}
+
+/// What to do with the files of a datastore being deleted
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeletePolicy {
+    /// Refuse to delete a datastore that still holds files
+    Refuse,
+    /// Delete the files, both objects and records
+    Cascade,
+    /// Move the files to another datastore first
+    Migrate(String),
+    /// Delete the records, leaving the objects in place
+    Forget,
+}
+
+#[derive(serde::Serialize)]
+pub struct DsStats {
+    pub id: String,
+    pub name: Option<String>,
+    /// Number of records in the datastore
+    pub files: usize,
+    /// Bytes stored, counting shared objects once
+    pub used: u64,
+    pub soft_quota: Option<u64>,
+    pub hard_quota: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GroupBy {
+    Datastore,
+    Tag,
+    /// The directory part of the raw name
+    Folder,
+}
+
+#[derive(serde::Serialize)]
+pub struct Usage {
+    pub key: String,
+    pub files: usize,
+    pub size: u64,
+}
+
+#[derive(serde::Serialize)]
+pub struct LifecycleReport {
+    /// Files deleted because their TTL ran out
+    pub expired: Vec<MetaRecord>,
+    /// Replicas deleted by a lifecycle rule
+    pub deleted: Vec<MetaRecord>,
+    /// Replicas moved by a lifecycle rule, as they are now
+    pub moved: Vec<MetaRecord>,
+    /// Why files failed to expire, or replicas to be deleted or moved
+    pub errors: Vec<String>,
+}
+
+/// A file to put with [`RM::put_batch`], see [`RM::put`]
+#[derive(Debug, Clone)]
+pub struct PutRequest {
+    pub dsid: Option<String>,
+    pub path: PathBuf,
+    pub raw: String,
+    pub tags: Vec<String>,
+    pub ttl: Option<Duration>,
+}
+
+/// A file to get with [`RM::get_batch`]
+#[derive(Debug, Clone)]
+pub struct GetRequest {
+    pub gid: String,
+    pub path: PathBuf,
+}
+
+#[derive(serde::Serialize)]
+pub struct RepairReport {
+    /// Replicas re-created or added, as they are now
+    pub repaired: Vec<MetaRecord>,
+    /// Replicas that failed to be re-created or added
+    pub failed: Vec<MetaRecord>,
+    /// Datastores that failed to list, whose replicas were taken as present
+    pub unlisted: Vec<String>,
+    /// Files without a replica left to repair from
+    pub lost: Vec<String>,
+}
+
+#[derive(serde::Serialize)]
+pub struct FsckReport {
+    /// Objects in the datastore without a record
+    pub orphans: Vec<String>,
+    /// Records without an object in the datastore
+    pub missing: Vec<MetaRecord>,
+}
+
+/// SHA-256 of a file, in hex
+fn hash(path: &Path) -> Result<String> {
+    let mut file = std::fs::File::open(path).map_err(|e| Error::FileError(e.to_string()))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher).map_err(|e| Error::FileError(e.to_string()))?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect())
+}
+
+/// Current Unix time in seconds
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Failed to get time")
+        .as_secs()
+}
+
+/// Modification time of a file in Unix time
+fn mtime(metadata: &std::fs::Metadata) -> Option<u64> {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+        .map(|x| x.as_secs())
+}
+
+/// Datastore names must not be mistaken for ids
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.parse::<i64>().is_ok() {
+        Err(anyhow::anyhow!("Invalid datastore name: {name:?}"))?;
+    }
+    Ok(())
 }
 
 pub fn init(r#type: &str, cfg: &str) {
@@ -18,95 +184,1178 @@ pub fn init(r#type: &str, cfg: &str) {
 impl RM {
     pub fn new(r#type: &str, cfg: &str) -> Self {
         let meta = meta::build(r#type, cfg).expect("Failed to build");
-        Self { meta }
+        Self {
+            meta: Arc::from(meta),
+            policy: Arc::new(Rules::default()),
+            replication: Replication::default(),
+            observer: Arc::new(|_| {}),
+            retry: None,
+            limit: None,
+            limiters: Arc::default(),
+            cache: None,
+        }
+    }
+
+    /// Set the observer of the progress of puts and gets
+    pub fn set_observer(&mut self, observer: impl Fn(&Progress) + Send + Sync + 'static) {
+        self.observer = Arc::new(observer);
+    }
+
+    /// Retry the operations of every datastore by `policy`, overriding their own
+    pub fn set_retry(&mut self, policy: RetryPolicy) {
+        self.retry = Some(policy);
+    }
+
+    /// Serve gets from `cache`, keeping the downloaded files in it
+    pub fn set_cache(&mut self, cache: Cache) {
+        self.cache = Some(Arc::new(cache));
+    }
+
+    /// Limit the transfers of all datastores together to `rate` bytes per second
+    pub fn set_limit_rate(&mut self, rate: Option<u64>) {
+        self.limit = rate.map(|x| Arc::new(ds::Limiter::new(x)));
+    }
+
+    /// A datastore retrying its operations by the override or its own policy,
+    /// with its transfers throttled by the global and its own limit
+    async fn ds(&self, dsid: &str) -> Result<ds::SafeDs> {
+        let ds = self.meta.ds_get(dsid).await?;
+        let record = self.meta.ds_record(dsid).await?;
+        let policy = match &self.retry {
+            Some(policy) => policy.clone(),
+            None => record.retry.unwrap_or_default(),
+        };
+        let mut limiters = self.limit.iter().cloned().collect::<Vec<_>>();
+        if let Some(rate) = record.limit_rate {
+            let mut own = self.limiters.lock().unwrap();
+            let limiter = own
+                .entry(dsid.to_string())
+                .or_insert_with(|| Arc::new(ds::Limiter::new(rate)));
+            // the rate was changed meanwhile
+            if limiter.rate() != rate {
+                *limiter = Arc::new(ds::Limiter::new(rate));
+            }
+            limiters.push(limiter.clone());
+        }
+        let ds = ds::SafeDs::new(Box::new(ds::Retrying::new(ds, policy)));
+        Ok(ds::SafeDs::new(Box::new(ds::Limited::new(ds, limiters))))
+    }
+
+    /// Set how many copies of each file to keep
+    pub fn set_replication(&mut self, replication: Replication) {
+        self.replication = replication;
     }
 
-    pub async fn ds_put(&mut self, r#type: &str, cfg: &str) {
-        self.meta.ds_put(r#type, cfg);
+    /// Datastores for the replicas of a file whose first copy goes to `dsid`
+    async fn replicas(&self, dsid: &str) -> Result<Vec<String>> {
+        let mut targets = vec![dsid.to_string()];
+        for ds in &self.replication.ds {
+            if targets.len() >= self.replication.copies {
+                break;
+            }
+            let ds = self.meta.ds_id(ds).await?;
+            if !targets.contains(&ds) {
+                targets.push(ds);
+            }
+        }
+        Ok(targets)
     }
 
-    pub async fn ds_del(&mut self, dsid: &str) {
-        self.meta.ds_del(dsid);
+    /// Set the policy choosing the datastore of files put without one
+    pub fn set_policy(&mut self, policy: impl Policy + 'static) {
+        self.policy = Arc::new(policy);
+    }
+
+    /// Choose a datastore for the file by the policy
+    pub async fn select(&self, path: &Path, tags: &[String]) -> Result<String> {
+        let size = std::fs::metadata(path)
+            .map_err(|e| Error::FileError(e.to_string()))?
+            .len();
+        let mut free = HashMap::new();
+        for ds in self.meta.ds_ls().await {
+            let used = self.meta.ds_usage(&ds.id).await;
+            let left = ds.hard_quota.map(|x| x.saturating_sub(used));
+            if let Some(name) = ds.name {
+                free.insert(name, left);
+            }
+            free.insert(ds.id, left);
+        }
+        let file = FileInfo {
+            path,
+            size,
+            tags,
+            free: &free,
+        };
+        let dsid = self
+            .policy
+            .select(&file)
+            .ok_or(Error::NotFound("No datastore selected".to_string()))?;
+        self.meta.ds_id(&dsid).await
+    }
+
+    pub async fn ds_put(&self, r#type: &str, name: Option<&str>, cfg: &str) -> Result<()> {
+        if let Some(name) = name {
+            check_name(name)?;
+        }
+        self.meta.ds_put(r#type, name, cfg).await
+    }
+
+    /// Rename a datastore or replace its configuration
+    pub async fn ds_update(&self, dsid: &str, name: Option<&str>, cfg: Option<&str>) -> Result<()> {
+        let dsid = self.meta.ds_id(dsid).await?;
+        if let Some(name) = name {
+            check_name(name)?;
+        }
+        self.meta.ds_update(&dsid, name, cfg).await
+    }
+
+    /// Check a datastore configuration before putting it
+    pub async fn ds_check(&self, r#type: &str, cfg: &str) -> Result<Vec<Check>> {
+        // a composite datastore may be made of any other, by id or name
+        let mut known = HashMap::new();
+        for part in ds::parts(r#type, cfg)? {
+            let dsid = self.meta.ds_id(&part).await?;
+            let cli = self
+                .meta
+                .ds_get(&dsid)
+                .await
+                .with_context(|| format!("Failed to build datastore {part}"))?;
+            known.insert(part, cli);
+        }
+        let ds = build(r#type, cfg, &|x| {
+            known
+                .get(x)
+                .cloned()
+                .ok_or(Error::NotFound(format!("Datastore {x} not found")).into())
+        })
+        .with_context(|| "Failed to build")?;
+        Ok(ds.health_check().await)
+    }
+
+    /// Check a datastore already put
+    pub async fn ds_test(&self, dsid: &str) -> Result<Vec<Check>> {
+        let dsid = self.meta.ds_id(dsid).await?;
+        let checks = self.meta.ds_get(&dsid).await?.health_check().await;
+        Ok(checks)
+    }
+
+    /// Refuse to treat the objects of a datastore as its own when it holds the
+    /// shards or chunks of a composite datastore
+    async fn check_not_part(&self, dsid: &str) -> Result<()> {
+        for ds in self.meta.ds_ls().await {
+            for part in ds::parts(&ds.r#type, &ds.cfg).unwrap_or_default() {
+                if self.meta.ds_id(&part).await.ok().as_deref() == Some(dsid) {
+                    Err(Error::InUse(format!(
+                        "Datastore {dsid} is part of datastore {}",
+                        ds.id
+                    )))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn ds_del(&self, dsid: &str, policy: DeletePolicy) -> Result<()> {
+        let dsid = &self.meta.ds_id(dsid).await?;
+        // composite datastores must not lose their shards or chunks
+        self.check_not_part(dsid).await?;
+        let records = self.meta.ls(None, Some(dsid), None).await;
+        match policy {
+            DeletePolicy::Refuse if !records.is_empty() => Err(Error::InUse(format!(
+                "Datastore {dsid} still holds {} files",
+                records.len()
+            )))?,
+            DeletePolicy::Refuse => {}
+            DeletePolicy::Cascade => {
+                for mr in records {
+                    self.del_replica(&mr).await?;
+                }
+            }
+            DeletePolicy::Migrate(to) => {
+                self.migrate(dsid, &to).await?;
+            }
+            DeletePolicy::Forget => {
+                for mr in records {
+                    self.meta.del(&mr.gid, Some(dsid)).await;
+                }
+            }
+        }
+        self.meta.ds_del(dsid).await
     }
 
     pub async fn ds_ls(&self) -> Vec<DataStorageRecord> {
-        self.meta.ds_ls()
+        self.meta.ds_ls().await
+    }
+
+    /// Set the quotas of a datastore, `None` removing them
+    pub async fn ds_quota(&self, dsid: &str, soft: Option<u64>, hard: Option<u64>) -> Result<()> {
+        let dsid = self.meta.ds_id(dsid).await?;
+        self.meta.ds_quota(&dsid, soft, hard).await
     }
 
-    pub async fn put(&mut self, dsid: &str, path: &Path, raw: &str) -> Result<MetaRecord> {
+    /// Set the lifecycle rules of a datastore, replacing the previous ones
+    pub async fn ds_lifecycle(&self, dsid: &str, rules: &[Lifecycle]) -> Result<()> {
+        let dsid = self.meta.ds_id(dsid).await?;
+        for rule in rules {
+            if let Lifecycle::Move { ds, .. } = rule {
+                if self.meta.ds_id(ds).await? == dsid {
+                    Err(anyhow::anyhow!(
+                        "Datastore {dsid} cannot move files to itself"
+                    ))?;
+                }
+            }
+        }
+        self.meta.ds_lifecycle(&dsid, rules).await
+    }
+
+    /// Set the retry policy of a datastore, `None` restoring the default one
+    pub async fn ds_retry(&self, dsid: &str, policy: Option<&RetryPolicy>) -> Result<()> {
+        let dsid = self.meta.ds_id(dsid).await?;
+        self.meta.ds_retry(&dsid, policy).await
+    }
+
+    /// Limit the transfers of a datastore to `rate` bytes per second, `None` removing the limit
+    pub async fn ds_limit_rate(&self, dsid: &str, rate: Option<u64>) -> Result<()> {
+        let dsid = self.meta.ds_id(dsid).await?;
+        self.meta.ds_limit_rate(&dsid, rate).await
+    }
+
+    /// Files, usage and quotas of every datastore
+    pub async fn ds_stats(&self) -> Vec<DsStats> {
+        let mut stats = Vec::new();
+        for ds in self.meta.ds_ls().await {
+            stats.push(DsStats {
+                files: self.meta.ls(None, Some(&ds.id), None).await.len(),
+                used: self.meta.ds_usage(&ds.id).await,
+                id: ds.id,
+                name: ds.name,
+                soft_quota: ds.soft_quota,
+                hard_quota: ds.hard_quota,
+            });
+        }
+        stats
+    }
+
+    /// Total size of the files, grouped by datastore, tag or folder
+    ///
+    /// A file is counted in each of its tags and replicas; files of unknown
+    /// size count as empty.
+    pub async fn du(&self, by: GroupBy) -> Vec<Usage> {
+        let mut usage: Vec<Usage> = Vec::new();
+        for mr in self.meta.ls(None, None, None).await {
+            let keys = match by {
+                GroupBy::Datastore => vec![mr.dsid.clone()],
+                GroupBy::Tag => mr.tags.clone(),
+                GroupBy::Folder => vec![mr
+                    .raw
+                    .rsplit_once('/')
+                    .map(|x| x.0.to_string())
+                    .unwrap_or_default()],
+            };
+            for key in keys {
+                let i = match usage.iter().position(|x| x.key == key) {
+                    Some(i) => i,
+                    None => {
+                        usage.push(Usage {
+                            key,
+                            files: 0,
+                            size: 0,
+                        });
+                        usage.len() - 1
+                    }
+                };
+                usage[i].files += 1;
+                usage[i].size += mr.size.unwrap_or(0);
+            }
+        }
+        usage
+    }
+
+    /// Put a file, choosing the datastore by the policy if none is given
+    ///
+    /// With the `hash` raw type the object is named by its content, and a
+    /// datastore already holding it only gets a new record pointing at it.
+    /// With the `raw` type an earlier version of the file, put under the same
+    /// name, is replaced, and a file whose name is taken by an object put
+    /// otherwise is refused.
+    /// The file is replicated to further datastores by the replication
+    /// policy; a failed replica is only warned about, see [`RM::repair`].
+    /// A file put with a TTL is deleted by [`RM::run_lifecycle`] once it expires.
+    pub async fn put(
+        &self,
+        dsid: Option<&str>,
+        path: &Path,
+        raw: &str,
+        tags: &[String],
+        ttl: Option<Duration>,
+    ) -> Result<MetaRecord> {
+        let dsid = &match dsid {
+            Some(dsid) => self.meta.ds_id(dsid).await?,
+            None => self.select(path, tags).await?,
+        };
         let name = path
             .file_name()
             .and_then(|x| x.to_str())
             .map(|x| x.to_string())
             .unwrap();
         let uuid = uuid::Uuid::new_v4().to_string();
+        let hash = hash(path)?;
         let raw_name = match raw {
             "raw" => name.clone(),
             "gid" => uuid.clone(),
             "gide" => uuid.clone() + "." + path.extension().and_then(|x| x.to_str()).unwrap_or(""),
+            "hash" => hash.clone(),
             _ => Err(anyhow::anyhow!("Unknown raw type"))?,
         };
-        let desc = self
-            .meta
-            .ds_get(dsid)?
-            .lock()
-            .await
-            .put(raw_name.clone(), path)
-            .await
-            .with_context(|| "Failed to put")?;
+        // an earlier version of the file is replaced, while an object put
+        // otherwise, e.g. imported, must not be overwritten
+        let mut previous = Vec::new();
+        if raw == "raw" {
+            for dsid in self.replicas(dsid).await? {
+                for mr in self.meta.refs(&dsid, &raw_name).await {
+                    if mr.hash.is_none() || mr.name != name {
+                        Err(Error::InUse(format!(
+                            "Object {raw_name} in datastore {dsid} holds another file"
+                        )))?;
+                    }
+                    previous.push(mr);
+                }
+            }
+        }
+        let metadata = std::fs::metadata(path).map_err(|e| Error::FileError(e.to_string()))?;
         let mr = MetaRecord {
             gid: uuid,
             dsid: dsid.to_string(),
             name,
             raw: raw_name,
-            desc,
+            desc: String::new(),
+            hash: Some(hash),
+            size: Some(metadata.len()),
+            tags: tags.to_vec(),
+            created: Some(now()),
+            expires: ttl.map(|x| now() + x.as_secs()),
+            mtime: mtime(&metadata),
         };
-        self.meta.put(mr.clone());
+        let mr = self.put_record(mr, path).await?;
+        // the object was overwritten in place, only the records are stale,
+        // except where putting the replica failed
+        let stored = self.meta.ls(Some(&mr.gid), None, None).await;
+        for previous in previous {
+            if stored.iter().any(|x| x.dsid == previous.dsid) {
+                self.meta.del(&previous.gid, Some(&previous.dsid)).await;
+            }
+        }
+        Ok(mr)
+    }
+
+    /// Store a new record and its replicas
+    async fn put_record(&self, mr: MetaRecord, path: &Path) -> Result<MetaRecord> {
+        let dsid = &mr.dsid;
+        let targets = self.replicas(dsid).await?;
+        let desc = self.store(&mr, dsid, path).await?;
+        let mr = MetaRecord { desc, ..mr };
+        self.meta.put(mr.clone()).await;
+        for dsid in &targets[1..] {
+            match self.store(&mr, dsid, path).await {
+                Ok(desc) => {
+                    self.meta
+                        .put(MetaRecord {
+                            dsid: dsid.clone(),
+                            desc,
+                            ..mr.clone()
+                        })
+                        .await
+                }
+                Err(e) => tracing::warn!("Failed to put replica to {dsid}: {e:#}"),
+            }
+        }
         Ok(mr)
     }
 
+    /// Put the object of a record to a datastore, unless it is already there
+    async fn store(&self, mr: &MetaRecord, dsid: &str, path: &Path) -> Result<String> {
+        if let Some(desc) = self.shared(mr, dsid).await {
+            return Ok(desc);
+        }
+        self.check_quota(dsid, mr.size.unwrap_or(0)).await?;
+        self.ds(dsid)
+            .await?
+            .put_with_progress(mr.raw.clone(), path, &*self.observer)
+            .await
+            .with_context(|| "Failed to put")
+    }
+
+    /// Reject a put of `size` bytes above the hard quota, warn above the soft one
+    async fn check_quota(&self, dsid: &str, size: u64) -> Result<()> {
+        let ds = self.meta.ds_record(dsid).await?;
+        let used = self.meta.ds_usage(dsid).await + size;
+        if let Some(hard) = ds.hard_quota.filter(|x| used > *x) {
+            Err(Error::QuotaExceeded(format!(
+                "Datastore {dsid} would hold {used} of {hard} bytes"
+            )))?;
+        }
+        if let Some(soft) = ds.soft_quota.filter(|x| used > *x) {
+            tracing::warn!("Datastore {dsid} holds {used} bytes, above its soft quota of {soft}");
+        }
+        Ok(())
+    }
+
+    /// Description of the same content already stored under the same name in `dsid`
+    async fn shared(&self, mr: &MetaRecord, dsid: &str) -> Option<String> {
+        self.meta
+            .refs(dsid, &mr.raw)
+            .await
+            .into_iter()
+            .find(|x| x.hash.is_some() && x.hash == mr.hash)
+            .map(|x| x.desc)
+    }
+
     pub async fn get(
-        &mut self,
+        &self,
         gid: Option<&str>,
         dsid: Option<&str>,
         name: Option<&str>,
         path: Option<&Path>,
     ) -> Result<()> {
-        let mr = self.meta.ls(gid, dsid, name);
+        let mr = self.ls(gid, dsid, name).await;
         let mr = mr.first().with_context(|| "Not found")?;
+        let local = path
+            .map(|x| x.to_path_buf())
+            .unwrap_or(PathBuf::from(&mr.raw));
+        // a datastore asked for is read from, even if the file is cached
+        if let (Some(cache), None) = (&self.cache, dsid) {
+            match cache.fetch(mr, &local) {
+                Ok(true) => return Ok(()),
+                Ok(false) => {}
+                Err(e) => tracing::warn!("Failed to read cache: {e:#}"),
+            }
+        }
 
-        self.meta
-            .ds_get(&mr.dsid)
-            .unwrap()
-            .lock()
+        // fall back to the other replicas, unless a datastore was asked for
+        let replicas = match dsid {
+            Some(_) => vec![mr.clone()],
+            None => self.meta.ls(Some(&mr.gid), None, None).await,
+        };
+        let mut err = None;
+        for mr in replicas {
+            let res = match self.ds(&mr.dsid).await {
+                Ok(ds) => {
+                    ds.get_with_progress(mr.raw.clone(), path, &*self.observer)
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            match res {
+                Ok(()) => {
+                    if let Some(cache) = &self.cache {
+                        if let Err(e) = cache.store(&mr, &local) {
+                            tracing::warn!("Failed to cache {}: {e:#}", mr.gid);
+                        }
+                    }
+                    return Ok(());
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to get replica from {}: {e:#}", mr.dsid);
+                    err = Some(e);
+                }
+            }
+        }
+        Err(err.with_context(|| "Not found")?).with_context(|| "Failed to get")
+    }
+
+    /// Put files, at most `limit` at a time, returning the results in order
+    pub async fn put_batch(
+        &self,
+        requests: &[PutRequest],
+        limit: usize,
+    ) -> Vec<Result<MetaRecord>> {
+        futures::stream::iter(requests)
+            .map(|x| self.put(x.dsid.as_deref(), &x.path, &x.raw, &x.tags, x.ttl))
+            .buffered(limit.max(1))
+            .collect()
             .await
-            .get(mr.raw.clone(), path)
+    }
+
+    /// Get files, at most `limit` at a time, returning the results in order
+    pub async fn get_batch(&self, requests: &[GetRequest], limit: usize) -> Vec<Result<()>> {
+        futures::stream::iter(requests)
+            .map(|x| self.get(Some(&x.gid), None, None, Some(&x.path)))
+            .buffered(limit.max(1))
+            .collect()
             .await
-            .with_context(|| "Failed to get")?;
+    }
+
+    /// Delete a file with all its replicas
+    pub async fn del(&self, gid: &str) -> Result<()> {
+        let replicas = self.meta.ls(Some(gid), None, None).await;
+        if replicas.is_empty() {
+            Err(anyhow::anyhow!("Not found"))?;
+        }
+        for mr in replicas {
+            self.del_replica(&mr).await?;
+        }
+        Ok(())
+    }
+
+    async fn del_replica(&self, mr: &MetaRecord) -> Result<()> {
+        // the object may be shared with other records
+        if self.meta.refs(&mr.dsid, &mr.raw).await.len() <= 1 {
+            self.del_object(&mr.dsid, &mr.raw).await?;
+        }
+        self.meta.del(&mr.gid, Some(&mr.dsid)).await;
         Ok(())
     }
 
-    pub async fn del(&mut self, gid: &str) -> Result<()> {
-        let mr = self.meta.ls(Some(gid), None, None);
+    /// Delete an object no record refers to anymore
+    ///
+    /// Read-only datastores keep their objects, which are only untracked.
+    async fn del_object(&self, dsid: &str, raw: &str) -> Result<()> {
+        match self.ds(dsid).await?.del(raw.to_string()).await {
+            Err(e) if matches!(e.downcast_ref::<Error>(), Some(Error::Unsupported(_))) => {
+                tracing::warn!("Leaving {raw} in {dsid}: {e:#}");
+                Ok(())
+            }
+            res => res.with_context(|| "Failed to del"),
+        }
+    }
+    /// Copy a file to another datastore as a new record
+    pub async fn copy(&self, gid: &str, dsid: &str) -> Result<MetaRecord> {
+        let dsid = &self.meta.ds_id(dsid).await?;
+        let mr = self.meta.ls(Some(gid), None, None).await;
+        let mr = mr.first().with_context(|| "Not found")?;
+        let desc = match self.shared(mr, dsid).await {
+            Some(desc) => desc,
+            None => self.transfer(mr, dsid).await?,
+        };
+        let mr = MetaRecord {
+            gid: uuid::Uuid::new_v4().to_string(),
+            dsid: dsid.to_string(),
+            desc,
+            ..mr.clone()
+        };
+        self.meta.put(mr.clone()).await;
+        Ok(mr)
+    }
+
+    /// Move a file to another datastore, keeping its gid
+    ///
+    /// Only the first replica is moved.
+    pub async fn move_to(&self, gid: &str, dsid: &str) -> Result<MetaRecord> {
+        let dsid = &self.meta.ds_id(dsid).await?;
+        let mr = self.meta.ls(Some(gid), None, None).await;
         let mr = mr.first().with_context(|| "Not found")?;
+        self.move_replica(mr, dsid).await
+    }
 
-        self.meta
-            .ds_get(&mr.dsid)
-            .unwrap()
-            .lock()
+    /// Move every file of a datastore to another one
+    pub async fn migrate(&self, from: &str, to: &str) -> Result<Vec<MetaRecord>> {
+        let from = self.meta.ds_id(from).await?;
+        let to = self.meta.ds_id(to).await?;
+        let mut moved = Vec::new();
+        for mr in self.meta.ls(None, Some(&from), None).await {
+            moved.push(self.move_replica(&mr, &to).await?);
+        }
+        Ok(moved)
+    }
+
+    async fn move_replica(&self, mr: &MetaRecord, dsid: &str) -> Result<MetaRecord> {
+        if mr.dsid == dsid {
+            return Ok(mr.clone());
+        }
+        // the target may already hold another replica of the file
+        if let Some(existing) = self.meta.ls(Some(&mr.gid), Some(dsid), None).await.pop() {
+            self.del_replica(mr).await?;
+            return Ok(existing);
+        }
+        let desc = match self.shared(mr, dsid).await {
+            Some(desc) => desc,
+            None => self.transfer(mr, dsid).await?,
+        };
+        let moved = MetaRecord {
+            dsid: dsid.to_string(),
+            desc,
+            ..mr.clone()
+        };
+        self.meta.update(&mr.dsid, moved.clone()).await;
+        if self.meta.refs(&mr.dsid, &mr.raw).await.is_empty() {
+            self.del_object(&mr.dsid, &mr.raw).await?;
+        }
+        Ok(moved)
+    }
+
+    /// Delete expired files and apply the lifecycle rules of every datastore
+    ///
+    /// Of the rules a replica is old enough for, the one with the most days
+    /// applies. Files without a known put time are left alone.
+    pub async fn run_lifecycle(&self) -> Result<LifecycleReport> {
+        let now = now();
+        let mut report = LifecycleReport {
+            expired: Vec::new(),
+            deleted: Vec::new(),
+            moved: Vec::new(),
+            errors: Vec::new(),
+        };
+        let mut tried = HashSet::new();
+        for mr in self.meta.ls(None, None, None).await {
+            if mr.expires.is_some_and(|x| x <= now) && tried.insert(mr.gid.clone()) {
+                match self.del(&mr.gid).await {
+                    Ok(()) => report.expired.push(mr),
+                    Err(e) => report
+                        .errors
+                        .push(format!("Failed to delete expired {}: {e:#}", mr.gid)),
+                }
+            }
+        }
+        for ds in self.meta.ds_ls().await {
+            if ds.lifecycle.is_empty() {
+                continue;
+            }
+            for mr in self.meta.ls(None, Some(&ds.id), None).await {
+                let Some(age) = mr.created.map(|x| now.saturating_sub(x) / 86400) else {
+                    continue;
+                };
+                let rule = ds
+                    .lifecycle
+                    .iter()
+                    .filter(|x| x.after_days() <= age)
+                    .max_by_key(|x| x.after_days());
+                match rule {
+                    Some(Lifecycle::Delete { .. }) => match self.del_replica(&mr).await {
+                        Ok(()) => report.deleted.push(mr),
+                        Err(e) => report
+                            .errors
+                            .push(format!("Failed to delete {} in {}: {e:#}", mr.gid, mr.dsid)),
+                    },
+                    Some(Lifecycle::Move { ds, .. }) => {
+                        let moved = match self.meta.ds_id(ds).await {
+                            Ok(to) => self.move_replica(&mr, &to).await,
+                            Err(e) => Err(e),
+                        };
+                        match moved {
+                            Ok(moved) => report.moved.push(moved),
+                            Err(e) => report.errors.push(format!(
+                                "Failed to move {} from {} to {ds}: {e:#}",
+                                mr.gid, mr.dsid
+                            )),
+                        }
+                    }
+                    None => {}
+                }
+            }
+        }
+        for e in &report.errors {
+            tracing::warn!("{e}");
+        }
+        Ok(report)
+    }
+
+    /// Re-create replicas whose object vanished, and replicas missing to
+    /// reach the number of copies of the replication policy
+    ///
+    /// Failures are warned about and reported, and do not stop the repair of
+    /// the other files.
+    pub async fn repair(&self) -> Result<RepairReport> {
+        let mut report = RepairReport {
+            repaired: Vec::new(),
+            failed: Vec::new(),
+            unlisted: Vec::new(),
+            lost: Vec::new(),
+        };
+        let mut objects = HashMap::new();
+        for ds in self.meta.ds_ls().await {
+            let names = match self.ds(&ds.id).await {
+                Ok(store) => store.list(None).await,
+                Err(e) => Err(e),
+            };
+            match names {
+                Ok(names) => {
+                    objects.insert(ds.id, names.into_iter().collect::<HashSet<_>>());
+                }
+                Err(e) if matches!(e.downcast_ref::<Error>(), Some(Error::Unsupported(_))) => {
+                    tracing::warn!("Taking the replicas of {} as present: {e:#}", ds.id);
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to list {}, skipping its replicas: {e:#}", ds.id);
+                    report.unlisted.push(ds.id);
+                }
+            }
+        }
+        // replicas on datastores that cannot list are not known to be missing
+        let exists = |mr: &MetaRecord| objects.get(&mr.dsid).is_none_or(|x| x.contains(&mr.raw));
+
+        let mut files: HashMap<String, Vec<MetaRecord>> = HashMap::new();
+        for mr in self.meta.ls(None, None, None).await {
+            files.entry(mr.gid.clone()).or_default().push(mr);
+        }
+        for (gid, replicas) in files {
+            let (healthy, broken): (Vec<_>, Vec<_>) = replicas.into_iter().partition(exists);
+            let Some(source) = healthy.first() else {
+                tracing::warn!("No replica of {gid} left to repair from");
+                report.lost.push(gid);
+                continue;
+            };
+            for mr in &broken {
+                match self.transfer(source, &mr.dsid).await {
+                    Ok(desc) => {
+                        let mr = MetaRecord { desc, ..mr.clone() };
+                        self.meta.update(&mr.dsid, mr.clone()).await;
+                        report.repaired.push(mr);
+                    }
+                    Err(e) => {
+                        tracing::warn!("Failed to repair {gid} in {}: {e:#}", mr.dsid);
+                        report.failed.push(mr.clone());
+                    }
+                }
+            }
+            let held = healthy
+                .iter()
+                .chain(&broken)
+                .map(|x| x.dsid.clone())
+                .collect::<Vec<_>>();
+            let targets = match self.replicas(&source.dsid).await {
+                Ok(targets) => targets,
+                Err(e) => {
+                    tracing::warn!("Failed to resolve the replicas of {gid}: {e:#}");
+                    continue;
+                }
+            };
+            let mut copies = held.len();
+            for ds in targets {
+                if copies >= self.replication.copies {
+                    break;
+                }
+                if held.contains(&ds) {
+                    continue;
+                }
+                let mr = MetaRecord {
+                    dsid: ds,
+                    ..source.clone()
+                };
+                match self.transfer(source, &mr.dsid).await {
+                    Ok(desc) => {
+                        let mr = MetaRecord { desc, ..mr };
+                        self.meta.put(mr.clone()).await;
+                        report.repaired.push(mr);
+                        copies += 1;
+                    }
+                    Err(e) => {
+                        tracing::warn!("Failed to replicate {gid} to {}: {e:#}", mr.dsid);
+                        report.failed.push(mr);
+                    }
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    async fn transfer(&self, mr: &MetaRecord, dsid: &str) -> Result<String> {
+        if mr.dsid == dsid {
+            Err(anyhow::anyhow!("Source and target datastore are the same"))?;
+        }
+        // a broken replica being restored is already counted in the usage
+        let restored = !self
+            .meta
+            .ls(Some(&mr.gid), Some(dsid), None)
             .await
-            .del(mr.raw.clone())
+            .is_empty();
+        self.check_quota(dsid, if restored { 0 } else { mr.size.unwrap_or(0) })
+            .await?;
+        let from = self.ds(&mr.dsid).await?;
+        let to = self.ds(dsid).await?;
+        from.copy(mr.raw.clone(), &*to, mr.raw.clone())
             .await
-            .with_context(|| "Failed to del")?;
-        self.meta.del(gid);
-        Ok(())
+            .with_context(|| "Failed to copy")
     }
+
     pub async fn ls(
-        &mut self,
+        &self,
         gid: Option<&str>,
         dsid: Option<&str>,
         name: Option<&str>,
     ) -> Vec<MetaRecord> {
-        self.meta.ls(gid, dsid, name)
+        let dsid = match dsid {
+            Some(dsid) => match self.meta.ds_id(dsid).await {
+                Ok(dsid) => Some(dsid),
+                Err(_) => return Vec::new(),
+            },
+            None => None,
+        };
+        self.meta.ls(gid, dsid.as_deref(), name).await
+    }
+
+    pub async fn fsck(&self, dsid: &str, repair: &[Repair]) -> Result<FsckReport> {
+        let dsid = &self.meta.ds_id(dsid).await?;
+        // the shards and chunks of a composite datastore are no orphans
+        if repair.contains(&Repair::Import) || repair.contains(&Repair::Delete) {
+            self.check_not_part(dsid).await?;
+        }
+        let ds = self.ds(dsid).await?;
+        let objects = ds.list(None).await.with_context(|| "Failed to list")?;
+        let records = self.meta.ls(None, Some(dsid), None).await;
+
+        let known = records
+            .iter()
+            .map(|x| x.raw.as_str())
+            .collect::<HashSet<_>>();
+        let orphans = objects
+            .iter()
+            .filter(|x| !known.contains(x.as_str()))
+            .cloned()
+            .collect::<Vec<_>>();
+        let objects = objects.iter().map(|x| x.as_str()).collect::<HashSet<_>>();
+        let missing = records
+            .iter()
+            .filter(|x| !objects.contains(x.raw.as_str()))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if repair.contains(&Repair::Import) {
+            let orphans = orphans
+                .iter()
+                .map(|x| (x.clone(), None))
+                .collect::<Vec<_>>();
+            self.record(dsid, &orphans).await;
+        } else if repair.contains(&Repair::Delete) {
+            for raw in &orphans {
+                ds.del(raw.clone()).await.with_context(|| "Failed to del")?;
+            }
+        }
+        if repair.contains(&Repair::Forget) {
+            for mr in &missing {
+                self.meta.del(&mr.gid, Some(dsid)).await;
+            }
+        }
+        Ok(FsckReport { orphans, missing })
+    }
+
+    /// Create records for objects already in the datastore, skipping known ones
+    pub async fn import(&self, dsid: &str, prefix: Option<&str>) -> Result<Vec<MetaRecord>> {
+        let dsid = &self.meta.ds_id(dsid).await?;
+        self.check_not_part(dsid).await?;
+        let objects = self
+            .ds(dsid)
+            .await?
+            .list(prefix)
+            .await
+            .with_context(|| "Failed to list")?;
+        let objects = self
+            .untracked(dsid, &objects)
+            .await
+            .into_iter()
+            .map(|x| (x, None))
+            .collect::<Vec<_>>();
+        Ok(self.record(dsid, &objects).await)
+    }
+
+    /// Create records for the named objects of the datastore, skipping known ones
+    ///
+    /// Unlike [`RM::import`], this works for datastores that cannot list. Every
+    /// object must exist, and is recorded with its size if the datastore knows it.
+    pub async fn track(&self, dsid: &str, raws: &[String]) -> Result<Vec<MetaRecord>> {
+        let dsid = &self.meta.ds_id(dsid).await?;
+        let ds = self.ds(dsid).await?;
+        let mut objects = Vec::new();
+        for raw in self.untracked(dsid, raws).await {
+            let size = ds
+                .stat(raw.clone())
+                .await
+                .with_context(|| format!("Failed to find {raw}"))?;
+            objects.push((raw, size));
+        }
+        Ok(self.record(dsid, &objects).await)
+    }
+
+    /// Those of the objects without a record in the datastore
+    async fn untracked(&self, dsid: &str, raws: &[String]) -> Vec<String> {
+        let records = self.meta.ls(None, Some(dsid), None).await;
+        let known = records
+            .iter()
+            .map(|x| x.raw.as_str())
+            .collect::<HashSet<_>>();
+        raws.iter()
+            .filter(|x| !known.contains(x.as_str()))
+            .cloned()
+            .collect()
+    }
+
+    /// Create records for objects, with their sizes if known
+    async fn record(&self, dsid: &str, objects: &[(String, Option<u64>)]) -> Vec<MetaRecord> {
+        let mut records = Vec::new();
+        for (raw, size) in objects {
+            let mr = MetaRecord {
+                gid: uuid::Uuid::new_v4().to_string(),
+                dsid: dsid.to_string(),
+                name: raw.rsplit('/').next().unwrap_or(raw).to_string(),
+                raw: raw.clone(),
+                desc: String::new(),
+                hash: None,
+                size: *size,
+                tags: Vec::new(),
+                created: Some(now()),
+                expires: None,
+                mtime: None,
+            };
+            self.meta.put(mr.clone()).await;
+            records.push(mr);
+        }
+        records
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    /// An RM on a fresh database, with a read-only HTTP datastore `artifacts`
+    async fn setup(url: &str) -> (RM, String) {
+        let db = std::env::temp_dir().join(format!("easy-fm-{}.db", uuid::Uuid::new_v4()));
+        let db = db.to_str().unwrap().to_string();
+        init("local", &db);
+        let rm = RM::new("local", &db);
+        let cfg = serde_json::json!({ "url": url }).to_string();
+        rm.ds_put("http", Some("artifacts"), &cfg).await.unwrap();
+        (rm, db)
+    }
+
+    /// Answer HEAD of `/files/app` with a length of 3, and anything else with 404
+    async fn serve() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut conn, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0; 4096];
+                let n = conn.read(&mut buf).await.unwrap();
+                let res = match buf[..n].starts_with(b"HEAD /files/app ") {
+                    true => "HTTP/1.1 200 OK\r\ncontent-length: 3\r\n",
+                    false => "HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n",
+                };
+                let res = format!("{res}connection: close\r\n\r\n");
+                conn.write_all(res.as_bytes()).await.unwrap();
+            }
+        });
+        format!("http://{addr}/files")
+    }
+
+    /// Store the objects PUT below `/files` in memory, and serve them back
+    async fn serve_writable() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let objects = Arc::new(Mutex::new(HashMap::<String, Vec<u8>>::new()));
+        tokio::spawn(async move {
+            loop {
+                let (mut conn, _) = listener.accept().await.unwrap();
+                let objects = objects.clone();
+                tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    let mut chunk = vec![0; 4096];
+                    let end = loop {
+                        let n = conn.read(&mut chunk).await.unwrap();
+                        buf.extend_from_slice(&chunk[..n]);
+                        if let Some(i) = buf.windows(4).position(|x| x == b"\r\n\r\n") {
+                            break i + 4;
+                        }
+                    };
+                    let head = String::from_utf8_lossy(&buf[..end]).to_lowercase();
+                    let len = head
+                        .lines()
+                        .find_map(|x| x.strip_prefix("content-length:"))
+                        .map(|x| x.trim().parse::<usize>().unwrap())
+                        .unwrap_or(0);
+                    while buf.len() < end + len {
+                        let n = conn.read(&mut chunk).await.unwrap();
+                        buf.extend_from_slice(&chunk[..n]);
+                    }
+                    let mut words = head.split_whitespace();
+                    let (method, path) = (words.next().unwrap(), words.next().unwrap());
+                    let (status, body) = {
+                        let mut objects = objects.lock().unwrap();
+                        match (method, objects.get(path)) {
+                            ("put", _) => {
+                                objects.insert(path.to_string(), buf[end..end + len].to_vec());
+                                ("201 Created", Vec::new())
+                            }
+                            ("delete", _) => {
+                                objects.remove(path);
+                                ("204 No Content", Vec::new())
+                            }
+                            ("get" | "head", Some(object)) => ("200 OK", object.clone()),
+                            _ => ("404 Not Found", Vec::new()),
+                        }
+                    };
+                    let mut res = format!(
+                        "HTTP/1.1 {status}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                        body.len()
+                    )
+                    .into_bytes();
+                    if method == "get" {
+                        res.extend(body);
+                    }
+                    conn.write_all(&res).await.unwrap();
+                });
+            }
+        });
+        format!("http://{addr}/files")
+    }
+
+    #[tokio::test]
+    async fn del_untracks_read_only_objects() {
+        // nothing listens there, the object must not even be asked for
+        let (rm, db) = setup("http://127.0.0.1:9/files").await;
+        let dsid = rm.meta.ds_id("artifacts").await.unwrap();
+        let mr = rm
+            .record(&dsid, &[("app".to_string(), None)])
+            .await
+            .remove(0);
+        rm.del(&mr.gid).await.unwrap();
+        assert!(rm.ls(Some(&mr.gid), None, None).await.is_empty());
+        let _ = std::fs::remove_file(db);
+    }
+
+    #[tokio::test]
+    async fn track_checks_objects() {
+        let (rm, db) = setup(&serve().await).await;
+        let mrv = rm.track("artifacts", &["app".to_string()]).await.unwrap();
+        assert_eq!(mrv[0].size, Some(3));
+        // known objects are skipped
+        assert!(rm
+            .track("artifacts", &["app".to_string()])
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(rm.track("artifacts", &["typo".to_string()]).await.is_err());
+        assert_eq!(rm.ls(None, Some("artifacts"), None).await.len(), 1);
+        let _ = std::fs::remove_file(db);
+    }
+
+    #[tokio::test]
+    async fn composite_datastores() {
+        let (rm, db) = setup("http://127.0.0.1:9/files").await;
+        let over = |ds: &str| serde_json::json!({ "ds": ds }).to_string();
+        rm.ds_put("chunk", Some("outer"), &over("inner"))
+            .await
+            .unwrap();
+        rm.ds_put("chunk", Some("inner"), &over("artifacts"))
+            .await
+            .unwrap();
+        assert!(rm.ds_test("outer").await.is_ok());
+        // datastores other ones are made of cannot be deleted
+        assert!(rm.ds_del("artifacts", DeletePolicy::Refuse).await.is_err());
+        assert!(rm.ds_del("inner", DeletePolicy::Refuse).await.is_err());
+        // nor have their shards or chunks imported or deleted as orphans
+        let in_use = |err: anyhow::Error| matches!(err.downcast_ref(), Some(Error::InUse(_)));
+        assert!(in_use(rm.import("inner", None).await.err().unwrap()));
+        assert!(in_use(
+            rm.fsck("inner", &[Repair::Delete]).await.err().unwrap()
+        ));
+        assert!(in_use(
+            rm.fsck("artifacts", &[Repair::Import]).await.err().unwrap()
+        ));
+
+        rm.ds_update("inner", None, Some(&over("outer")))
+            .await
+            .unwrap();
+        let err = rm.ds_test("outer").await.err().unwrap();
+        assert!(format!("{err:#}").contains("made of itself"), "{err:#}");
+        let _ = std::fs::remove_file(db);
+    }
+
+    #[tokio::test]
+    async fn raw_names_are_not_overwritten() {
+        let (rm, db) = setup("http://127.0.0.1:9/files").await;
+        let dsid = rm.meta.ds_id("artifacts").await.unwrap();
+        rm.record(&dsid, &[("app".to_string(), None)]).await;
+        let path = std::env::temp_dir()
+            .join(uuid::Uuid::new_v4().to_string())
+            .join("app");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, b"app").unwrap();
+        let err = rm
+            .put(Some("artifacts"), &path, "raw", &[], None)
+            .await
+            .err()
+            .unwrap();
+        assert!(
+            matches!(err.downcast_ref(), Some(Error::InUse(_))),
+            "{err:#}"
+        );
+        assert_eq!(rm.ls(None, Some("artifacts"), None).await.len(), 1);
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+        let _ = std::fs::remove_file(db);
+    }
+
+    #[tokio::test]
+    async fn old_databases_are_migrated_on_open() {
+        let db = std::env::temp_dir().join(format!("easy-fm-{}.db", uuid::Uuid::new_v4()));
+        let db = db.to_str().unwrap().to_string();
+        // the schema of the first versions, never passed to `init`
+        rusqlite::Connection::open(&db)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE rm (id INTEGER PRIMARY KEY, type TEXT NOT NULL, cfg TEXT NOT NULL);
+                CREATE TABLE map (gid TEXT NOT NULL, dsid INTEGER NOT NULL, name TEXT NOT NULL,
+                    raw TEXT NOT NULL, discription TEXT NOT NULL);
+                INSERT INTO rm VALUES (1, 'http', '{\"url\": \"http://127.0.0.1:9\"}');
+                INSERT INTO map VALUES ('g', 1, 'app', 'app', 'http://127.0.0.1:9/app');",
+            )
+            .unwrap();
+        let rm = RM::new("local", &db);
+        assert_eq!(rm.ds_ls().await.len(), 1);
+        let records = rm.ls(None, None, None).await;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].name, "app");
+        let _ = std::fs::remove_file(db);
+    }
+
+    #[tokio::test]
+    async fn watched_files_are_replaced() {
+        let (rm, db) = setup("http://127.0.0.1:9/files").await;
+        let cfg = serde_json::json!({ "url": serve_writable().await, "writable": true });
+        rm.ds_put("http", Some("drop"), &cfg.to_string())
+            .await
+            .unwrap();
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = WatchConfig {
+            settle: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let path = dir.join("result.csv");
+        let uploaded = |content: &'static str| {
+            let (rm, path) = (&rm, &path);
+            async move {
+                std::fs::write(path, content).unwrap();
+                for _ in 0..100 {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    let records = rm.ls(None, Some("drop"), None).await;
+                    if records.iter().any(|x| x.size == Some(content.len() as u64)) {
+                        return records;
+                    }
+                }
+                panic!("{content} was not uploaded");
+            }
+        };
+        // the watch is polled first, so that it sees the file written
+        let records = tokio::select! {
+            biased;
+            res = rm.watch(&dir, "drop", &config) => panic!("Watch stopped: {res:?}"),
+            records = async {
+                uploaded("1,2").await;
+                uploaded("1,2,3").await
+            } => records,
+        };
+        // the modified file replaced the object and its record
+        assert_eq!(records.len(), 1);
+        let out = dir.join("out.csv");
+        rm.get(Some(&records[0].gid), None, None, Some(&out))
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&out).unwrap(), b"1,2,3");
+        let _ = std::fs::remove_dir_all(dir);
+        let _ = std::fs::remove_file(db);
     }
 }
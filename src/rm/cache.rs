@@ -0,0 +1,316 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::Result;
+
+use super::{hash, mtime, MetaRecord};
+use crate::error::Error;
+
+const STATS: &str = "stats.json";
+
+/// A file kept by a [`Cache`]
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct CacheEntry {
+    pub gid: String,
+    pub hash: String,
+    pub size: u64,
+    /// Unix time the entry was last stored or served
+    pub accessed: Option<u64>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub size: u64,
+    pub max_size: u64,
+    /// Gets served from the cache
+    pub hits: u64,
+    /// Gets of cacheable files that were downloaded
+    pub misses: u64,
+}
+
+/// Hits and misses, kept in the cache directory across runs
+#[derive(Default, serde::Deserialize, serde::Serialize)]
+struct Counters {
+    hits: u64,
+    misses: u64,
+}
+
+/// An on-disk cache of downloaded files, keyed by gid and content hash
+///
+/// Entries are checked against the hash before they are served, and the
+/// least recently used ones are evicted to stay below `max_size` bytes.
+pub struct Cache {
+    dir: PathBuf,
+    max_size: u64,
+    /// Guards the counters and evictions of this process
+    lock: Mutex<()>,
+}
+
+impl Cache {
+    pub fn new(dir: impl Into<PathBuf>, max_size: u64) -> Self {
+        Self {
+            dir: dir.into(),
+            max_size,
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn path(&self, gid: &str, hash: &str) -> PathBuf {
+        self.dir.join(format!("{gid}.{hash}"))
+    }
+
+    /// Copy the cached content of a file to `path`, returning whether it was cached
+    pub fn fetch(&self, mr: &MetaRecord, path: &Path) -> Result<bool> {
+        // files of unknown content cannot be validated
+        let Some(expected) = &mr.hash else {
+            return Ok(false);
+        };
+        let cached = self.path(&mr.gid, expected);
+        if !cached.is_file() {
+            self.count(false);
+            return Ok(false);
+        }
+        if hash(&cached)? != *expected {
+            tracing::warn!("Evicting corrupted cache entry {}", cached.display());
+            let _ = std::fs::remove_file(&cached);
+            self.count(false);
+            return Ok(false);
+        }
+        std::fs::copy(&cached, path).map_err(|e| Error::FileError(e.to_string()))?;
+        touch(&cached);
+        self.count(true);
+        Ok(true)
+    }
+
+    /// Keep a downloaded file, evicting the least recently used entries to fit
+    ///
+    /// A file not matching the hash of its record is not kept.
+    pub fn store(&self, mr: &MetaRecord, path: &Path) -> Result<()> {
+        let Some(expected) = &mr.hash else {
+            return Ok(());
+        };
+        let size = std::fs::metadata(path)
+            .map_err(|e| Error::FileError(e.to_string()))?
+            .len();
+        if size > self.max_size {
+            return Ok(());
+        }
+        std::fs::create_dir_all(&self.dir).map_err(|e| Error::FileError(e.to_string()))?;
+        // written aside first, so that a partial entry is never served
+        let tmp = self.dir.join(format!(".{}", uuid::Uuid::new_v4()));
+        std::fs::copy(path, &tmp).map_err(|e| {
+            let _ = std::fs::remove_file(&tmp);
+            Error::FileError(e.to_string())
+        })?;
+        let matches = hash(&tmp).map(|x| x == *expected);
+        if !matches.as_ref().is_ok_and(|x| *x) {
+            let _ = std::fs::remove_file(&tmp);
+            matches?;
+            tracing::warn!("Not caching {}, which does not match its hash", mr.gid);
+            return Ok(());
+        }
+        std::fs::rename(&tmp, self.path(&mr.gid, expected)).map_err(|e| {
+            let _ = std::fs::remove_file(&tmp);
+            Error::FileError(e.to_string())
+        })?;
+
+        let _lock = self.lock.lock().unwrap();
+        let mut entries = self.ls();
+        let mut used = entries.iter().map(|x| x.size).sum::<u64>();
+        // least recently used last
+        while used > self.max_size {
+            let Some(entry) = entries.pop() else { break };
+            if entry.gid == mr.gid && entry.hash == *expected {
+                continue;
+            }
+            let _ = std::fs::remove_file(self.path(&entry.gid, &entry.hash));
+            used -= entry.size;
+        }
+        Ok(())
+    }
+
+    /// The entries, most recently used first
+    pub fn ls(&self) -> Vec<CacheEntry> {
+        let Ok(dir) = std::fs::read_dir(&self.dir) else {
+            return Vec::new();
+        };
+        let mut entries = dir
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let (gid, hash) = name.split_once('.')?;
+                if gid.is_empty() || hash.len() != 64 {
+                    return None;
+                }
+                let metadata = entry.metadata().ok()?;
+                Some(CacheEntry {
+                    gid: gid.to_string(),
+                    hash: hash.to_string(),
+                    size: metadata.len(),
+                    accessed: mtime(&metadata),
+                })
+            })
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|x| std::cmp::Reverse(x.accessed));
+        entries
+    }
+
+    /// Remove every entry and reset the counters
+    pub fn clear(&self) -> Result<()> {
+        let _lock = self.lock.lock().unwrap();
+        for entry in self.ls() {
+            std::fs::remove_file(self.path(&entry.gid, &entry.hash))
+                .map_err(|e| Error::FileError(e.to_string()))?;
+        }
+        match std::fs::remove_file(self.dir.join(STATS)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(Error::FileError(e.to_string()))?
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.ls();
+        let counters = self.counters();
+        CacheStats {
+            entries: entries.len(),
+            size: entries.iter().map(|x| x.size).sum(),
+            max_size: self.max_size,
+            hits: counters.hits,
+            misses: counters.misses,
+        }
+    }
+
+    fn counters(&self) -> Counters {
+        std::fs::read_to_string(self.dir.join(STATS))
+            .ok()
+            .and_then(|x| serde_json::from_str(&x).ok())
+            .unwrap_or_default()
+    }
+
+    fn count(&self, hit: bool) {
+        let _lock = self.lock.lock().unwrap();
+        let mut counters = self.counters();
+        match hit {
+            true => counters.hits += 1,
+            false => counters.misses += 1,
+        }
+        // the counters are informational, losing them is fine
+        let _ = std::fs::create_dir_all(&self.dir).and_then(|_| {
+            std::fs::write(
+                self.dir.join(STATS),
+                serde_json::to_string(&counters).expect("Failed to serialize"),
+            )
+        });
+    }
+}
+
+/// Mark an entry as just used
+fn touch(path: &Path) {
+    let _ = std::fs::File::options()
+        .write(true)
+        .open(path)
+        .and_then(|f| f.set_modified(std::time::SystemTime::now()));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+
+    /// A file holding `data`, and a record of it
+    fn file(dir: &Path, gid: &str, data: &[u8]) -> (MetaRecord, PathBuf) {
+        let path = dir.join(gid);
+        std::fs::write(&path, data).unwrap();
+        let mr = MetaRecord {
+            gid: gid.to_string(),
+            dsid: "1".to_string(),
+            name: gid.to_string(),
+            raw: gid.to_string(),
+            desc: String::new(),
+            hash: Some(hash(&path).unwrap()),
+            size: Some(data.len() as u64),
+            tags: Vec::new(),
+            created: None,
+            expires: None,
+            mtime: None,
+        };
+        (mr, path)
+    }
+
+    /// Mark an entry as used `secs` seconds ago
+    fn age(cache: &Cache, mr: &MetaRecord, secs: u64) {
+        std::fs::File::options()
+            .write(true)
+            .open(cache.path(&mr.gid, mr.hash.as_ref().unwrap()))
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(secs))
+            .unwrap();
+    }
+
+    fn gids(cache: &Cache) -> Vec<String> {
+        cache.ls().into_iter().map(|x| x.gid).collect()
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let cache = Cache::new(dir.join("cache"), 10);
+        let (a, a_path) = file(&dir, "a", b"aaaa");
+        let (b, b_path) = file(&dir, "b", b"bbbb");
+        let (c, c_path) = file(&dir, "c", b"cccc");
+        cache.store(&a, &a_path).unwrap();
+        cache.store(&b, &b_path).unwrap();
+        age(&cache, &a, 20);
+        age(&cache, &b, 30);
+        // a was served since, so b is the least recently used
+        assert!(cache.fetch(&a, &dir.join("out")).unwrap());
+        cache.store(&c, &c_path).unwrap();
+        assert_eq!(gids(&cache), ["a", "c"].map(String::from).to_vec());
+        assert!(!cache.fetch(&b, &dir.join("out")).unwrap());
+
+        // a file larger than the cache is not kept, nor evicts anything
+        let (big, big_path) = file(&dir, "big", &[0; 11]);
+        cache.store(&big, &big_path).unwrap();
+        assert_eq!(gids(&cache).len(), 2);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn validates_hashes() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let cache = Cache::new(dir.join("cache"), 100);
+        let (a, a_path) = file(&dir, "a", b"aaaa");
+
+        // a download not matching its record is not kept
+        std::fs::write(&a_path, b"oops").unwrap();
+        cache.store(&a, &a_path).unwrap();
+        assert!(cache.ls().is_empty());
+
+        // a corrupted entry is evicted instead of served
+        std::fs::write(&a_path, b"aaaa").unwrap();
+        cache.store(&a, &a_path).unwrap();
+        std::fs::write(cache.path(&a.gid, a.hash.as_ref().unwrap()), b"oops").unwrap();
+        let out = dir.join("out");
+        assert!(!cache.fetch(&a, &out).unwrap());
+        assert!(!out.exists());
+        assert!(cache.ls().is_empty());
+
+        // files of unknown content are neither kept nor counted
+        let unknown = MetaRecord { hash: None, ..a };
+        cache.store(&unknown, &a_path).unwrap();
+        assert!(!cache.fetch(&unknown, &out).unwrap());
+        assert!(cache.ls().is_empty());
+        assert_eq!(cache.stats().misses, 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,33 +1,365 @@
-use std::path::Path;
+use std::{
+    any::Any,
+    future::Future,
+    path::{Component, Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// Result of one probe of a health check
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Check {
+    /// The operation probed, e.g. `write`
+    pub op: String,
+    #[serde(rename = "latency_ms", serialize_with = "millis")]
+    pub latency: Duration,
+    /// Why the operation failed, if it did
+    pub error: Option<String>,
+}
+
+fn millis<S: serde::Serializer>(d: &Duration, s: S) -> std::result::Result<S::Ok, S::Error> {
+    s.serialize_u128(d.as_millis())
+}
+
+impl Check {
+    /// Time a probe
+    pub async fn run<T>(
+        op: impl Into<String>,
+        f: impl Future<Output = Result<T>>,
+    ) -> (Self, Option<T>) {
+        let op = op.into();
+        let start = Instant::now();
+        let res = f.await;
+        let latency = start.elapsed();
+        match res {
+            Ok(v) => (
+                Self {
+                    op,
+                    latency,
+                    error: None,
+                },
+                Some(v),
+            ),
+            Err(e) => (
+                Self {
+                    op,
+                    latency,
+                    error: Some(format!("{e:#}")),
+                },
+                None,
+            ),
+        }
+    }
+}
+
+/// Progress of one transfer
+#[derive(Debug, Clone)]
+pub struct Progress {
+    /// The object transferred
+    pub name: String,
+    pub bytes: u64,
+    /// Size of the object, if known
+    pub total: Option<u64>,
+    /// Bytes per second since the start of the transfer
+    pub rate: f64,
+}
+
+/// Receives the progress of transfers
+pub type Observer = dyn Fn(&Progress) + Send + Sync;
+
+/// Counts the bytes of a transfer and reports them to an observer
+pub struct Meter<'a> {
+    observer: &'a Observer,
+    progress: Progress,
+    start: Instant,
+}
+
+impl<'a> Meter<'a> {
+    pub fn new(observer: &'a Observer, name: &str, total: Option<u64>) -> Self {
+        Self {
+            observer,
+            progress: Progress {
+                name: name.to_string(),
+                bytes: 0,
+                total,
+                rate: 0.0,
+            },
+            start: Instant::now(),
+        }
+    }
+
+    pub fn add(&mut self, bytes: u64) {
+        retry::progressed();
+        self.progress.bytes += bytes;
+        self.progress.rate = self.progress.bytes as f64 / self.start.elapsed().as_secs_f64();
+        (self.observer)(&self.progress);
+    }
+}
 
 #[async_trait::async_trait]
-pub trait DataStorage {
+pub trait DataStorage: Any {
     /// Get file from storage
     async fn get(&self, name: String, path: Option<&Path>) -> Result<()>;
     /// Put file to storage
     async fn put(&self, name: String, path: &Path) -> Result<String>;
+    /// Get file from storage, reporting the progress to `observer`
+    ///
+    /// Storages that cannot report progress while transferring report it once
+    /// done.
+    async fn get_with_progress(
+        &self,
+        name: String,
+        path: Option<&Path>,
+        observer: &Observer,
+    ) -> Result<()> {
+        let local = path
+            .map(|x| x.to_path_buf())
+            .unwrap_or(PathBuf::from(&name));
+        self.get(name.clone(), path).await?;
+        let len = std::fs::metadata(local).map(|x| x.len()).unwrap_or(0);
+        Meter::new(observer, &name, Some(len)).add(len);
+        Ok(())
+    }
+    /// Put file to storage, reporting the progress to `observer`
+    async fn put_with_progress(
+        &self,
+        name: String,
+        path: &Path,
+        observer: &Observer,
+    ) -> Result<String> {
+        let desc = self.put(name.clone(), path).await?;
+        let len = std::fs::metadata(path).map(|x| x.len()).unwrap_or(0);
+        Meter::new(observer, &name, Some(len)).add(len);
+        Ok(desc)
+    }
     /// delete file from storage
     async fn del(&self, name: String) -> Result<()>;
+    /// list file names in storage
+    async fn list(&self, prefix: Option<&str>) -> Result<Vec<String>>;
+    /// size of file in storage if known, failing with `NotFound` if it is missing
+    async fn stat(&self, name: String) -> Result<Option<u64>> {
+        match self.list(Some(&name)).await?.contains(&name) {
+            true => Ok(None),
+            false => Err(crate::error::Error::NotFound(format!(
+                "Object {name} not found"
+            )))?,
+        }
+    }
+    /// copy file to another storage, return the description of the copy
+    async fn copy(
+        &self,
+        name: String,
+        to: &(dyn DataStorage + Send + Sync),
+        raw: String,
+    ) -> Result<String> {
+        relay(self, name, to, raw).await
+    }
+    /// check connectivity and permissions of storage
+    async fn health_check(&self) -> Vec<Check>;
+    fn as_any(&self) -> &dyn Any;
+}
+
+/// Whether an object name, or a remote key, names a file below the directory
+/// or URL it is joined to
+pub(super) fn contained(name: &str) -> bool {
+    !name.is_empty()
+        && Path::new(name)
+            .components()
+            .all(|x| matches!(x, Component::Normal(_)))
+}
+
+/// A fresh path for a temporary file
+fn tmp() -> PathBuf {
+    std::env::temp_dir().join(uuid::Uuid::new_v4().to_string())
+}
+
+/// A temporary file, removed when dropped, so also when a transfer fails or
+/// is cancelled
+struct TmpFile(PathBuf);
+
+impl Drop for TmpFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Copy file between storages through a local temporary file
+async fn relay<D: DataStorage + Sync + ?Sized>(
+    from: &D,
+    name: String,
+    to: &(dyn DataStorage + Send + Sync),
+    raw: String,
+) -> Result<String> {
+    let tmp = TmpFile(tmp());
+    from.get(name, Some(&tmp.0)).await?;
+    to.put(raw, &tmp.0).await
 }
 
+mod chunk;
+mod ec;
+mod http;
+mod limit;
+mod retry;
 mod s3;
+mod sftp;
+mod stream;
+mod webdav;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+pub use chunk::ChunkConfig;
+pub use ec::ErasureConfig;
+pub use http::HttpConfig;
+pub use limit::{throttle, Limited, Limiter};
+pub use retry::{RetryPolicy, Retrying};
 pub use s3::S3config;
+pub use sftp::SftpConfig;
+pub use webdav::WebdavConfig;
 
+/// Build a storage, looking up the storages a composite one is made of
 pub fn build(
     r#type: &str,
     config: &str,
-) -> Result<Box<dyn DataStorage + Send + Sync>, serde_json::Error> {
+    lookup: &dyn Fn(&str) -> Result<SafeDs>,
+) -> Result<Box<dyn DataStorage + Send + Sync>> {
     match r#type {
         "s3" => {
-            let config: s3::S3config = serde_json::from_str(config).expect("Failed to deserialize");
+            let config: s3::S3config =
+                serde_json::from_str(config).with_context(|| "Failed to deserialize")?;
             Ok(Box::new(s3::S3::new(config)))
         }
-        _ => panic!("Unknown type"),
+        "sftp" => {
+            let config: sftp::SftpConfig =
+                serde_json::from_str(config).with_context(|| "Failed to deserialize")?;
+            Ok(Box::new(sftp::Sftp::new(config)))
+        }
+        "webdav" => {
+            let config: webdav::WebdavConfig =
+                serde_json::from_str(config).with_context(|| "Failed to deserialize")?;
+            Ok(Box::new(webdav::Webdav::new(config)))
+        }
+        "http" => {
+            let config: http::HttpConfig =
+                serde_json::from_str(config).with_context(|| "Failed to deserialize")?;
+            Ok(Box::new(http::Http::new(config)?))
+        }
+        "ec" => {
+            let config: ec::ErasureConfig =
+                serde_json::from_str(config).with_context(|| "Failed to deserialize")?;
+            let shards = config
+                .ds
+                .iter()
+                .map(|x| lookup(x))
+                .collect::<Result<Vec<_>>>()?;
+            Ok(Box::new(ec::Erasure::new(config, shards)?))
+        }
+        "chunk" => {
+            let config: chunk::ChunkConfig =
+                serde_json::from_str(config).with_context(|| "Failed to deserialize")?;
+            let ds = lookup(&config.ds)?;
+            Ok(Box::new(chunk::Chunked::new(config, ds)?))
+        }
+        _ => Err(anyhow::anyhow!("Unknown type: {}", r#type)),
+    }
+}
+
+/// The storages a composite one is made of, by id or name
+pub(super) fn parts(r#type: &str, config: &str) -> Result<Vec<String>> {
+    match r#type {
+        "ec" => {
+            let config: ec::ErasureConfig =
+                serde_json::from_str(config).with_context(|| "Failed to deserialize")?;
+            Ok(config.ds)
+        }
+        "chunk" => {
+            let config: chunk::ChunkConfig =
+                serde_json::from_str(config).with_context(|| "Failed to deserialize")?;
+            Ok(vec![config.ds])
+        }
+        _ => Ok(Vec::new()),
     }
 }
 
 mod safe;
 
 pub use safe::SafeDs;
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+    use crate::error::Error;
+
+    /// A storage keeping its objects in memory, for composite storages to be
+    /// tested on
+    #[derive(Clone, Default)]
+    pub(super) struct Memory {
+        pub(super) objects: Arc<Mutex<BTreeMap<String, Vec<u8>>>>,
+        /// Whether puts fail, as when the storage is unreachable
+        pub(super) broken: bool,
+    }
+
+    #[async_trait::async_trait]
+    impl DataStorage for Memory {
+        async fn get(&self, name: String, path: Option<&Path>) -> Result<()> {
+            let data = self.objects.lock().unwrap().get(&name).cloned();
+            let data = data.ok_or(Error::NotFound(format!("Object {name} not found")))?;
+            std::fs::write(path.unwrap_or(Path::new(&name)), data)
+                .map_err(|e| Error::FileError(e.to_string()))?;
+            Ok(())
+        }
+        async fn put(&self, name: String, path: &Path) -> Result<String> {
+            if self.broken {
+                Err(anyhow::anyhow!("Storage is broken"))?;
+            }
+            let data = std::fs::read(path).map_err(|e| Error::FileError(e.to_string()))?;
+            self.objects.lock().unwrap().insert(name.clone(), data);
+            Ok(name)
+        }
+        async fn del(&self, name: String) -> Result<()> {
+            self.objects.lock().unwrap().remove(&name);
+            Ok(())
+        }
+        async fn list(&self, prefix: Option<&str>) -> Result<Vec<String>> {
+            let objects = self.objects.lock().unwrap();
+            Ok(objects
+                .keys()
+                .filter(|x| x.starts_with(prefix.unwrap_or_default()))
+                .cloned()
+                .collect())
+        }
+        async fn health_check(&self) -> Vec<Check> {
+            Vec::new()
+        }
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    /// Bytes that do not repeat, unlike the chunks of a content-defined split
+    pub(super) fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut x = seed | 1;
+        (0..len)
+            .map(|_| {
+                // xorshift
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn contained_names() {
+        assert!(contained("a.txt"));
+        assert!(contained("dir/sub/a.txt"));
+        assert!(!contained(""));
+        assert!(!contained("/etc/passwd"));
+        assert!(!contained("../a.txt"));
+        assert!(!contained("dir/../../a.txt"));
+        assert!(!contained("./a.txt"));
+    }
+}
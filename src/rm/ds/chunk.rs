@@ -0,0 +1,305 @@
+use std::{any::Any, collections::HashSet, path::Path};
+
+use anyhow::{Context, Result};
+use fastcdc::v2020::{StreamCDC, AVERAGE_MAX, AVERAGE_MIN};
+use sha2::{Digest, Sha256};
+
+use crate::error::Error;
+
+use super::{tmp, Check, DataStorage, SafeDs, TmpFile};
+
+/// Prefix of the chunk objects, which are named by their SHA-256
+const CHUNKS: &str = "chunks/";
+/// Prefix of the manifests, which are named by the file
+const MANIFESTS: &str = "manifests/";
+
+fn default_avg_size() -> u32 {
+    1 << 20
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct ChunkConfig {
+    /// Datastore holding the chunks and manifests
+    pub ds: String,
+    /// Average chunk size in bytes, chunks are a quarter to four times as large
+    #[serde(default = "default_avg_size")]
+    pub avg_size: u32,
+}
+
+/// The object stored in place of a file
+#[derive(serde::Deserialize, serde::Serialize)]
+struct Manifest {
+    size: u64,
+    chunks: Vec<String>,
+}
+
+/// Splits files into content-defined chunks shared across files and versions
+///
+/// A file is stored as a manifest listing its chunks, so putting a modified
+/// file only uploads the chunks that changed.
+pub struct Chunked {
+    config: ChunkConfig,
+    ds: SafeDs,
+    /// Held shared by puts and exclusively by garbage collection, so that a
+    /// chunk a put found to exist is not collected before its manifest is written
+    gc: tokio::sync::RwLock<()>,
+}
+
+impl Chunked {
+    pub fn new(config: ChunkConfig, ds: SafeDs) -> Result<Self> {
+        if !(AVERAGE_MIN..=AVERAGE_MAX).contains(&config.avg_size) {
+            Err(anyhow::anyhow!(
+                "Average chunk size must be within {AVERAGE_MIN} and {AVERAGE_MAX}"
+            ))?;
+        }
+        Ok(Self {
+            config,
+            ds,
+            gc: tokio::sync::RwLock::new(()),
+        })
+    }
+
+    async fn read(&self, name: &str) -> Result<Vec<u8>> {
+        let tmp = tmp();
+        let res = self.ds.get(name.to_string(), Some(&tmp)).await;
+        let data = res
+            .and_then(|_| std::fs::read(&tmp).map_err(|e| Error::FileError(e.to_string()).into()));
+        let _ = std::fs::remove_file(&tmp);
+        data
+    }
+
+    async fn write(&self, name: &str, data: &[u8]) -> Result<String> {
+        let tmp = tmp();
+        std::fs::write(&tmp, data).map_err(|e| Error::FileError(e.to_string()))?;
+        let res = self.ds.put(name.to_string(), &tmp).await;
+        let _ = std::fs::remove_file(&tmp);
+        res
+    }
+
+    async fn manifest(&self, name: &str) -> Result<Manifest> {
+        let data = self.read(&(MANIFESTS.to_string() + name)).await?;
+        serde_json::from_slice(&data).with_context(|| format!("Malformed manifest {name}"))
+    }
+
+    /// Whether the file has a manifest
+    async fn exists(&self, name: &str) -> Result<bool> {
+        let names = self.list(Some(name)).await?;
+        Ok(names.iter().any(|x| x == name))
+    }
+
+    /// Delete those of the chunks no manifest refers to anymore
+    async fn collect(&self, chunks: Vec<String>) -> Result<()> {
+        let _gc = self.gc.write().await;
+        let mut garbage = chunks.into_iter().collect::<HashSet<_>>();
+        for name in self.list(None).await? {
+            // a manifest that cannot be read may hold on to any chunk, one
+            // that cannot be parsed is no manifest
+            let manifest = match self.manifest(&name).await {
+                Ok(manifest) => manifest,
+                Err(e) if e.downcast_ref::<serde_json::Error>().is_some() => {
+                    tracing::warn!("Skipping {name}: {e:#}");
+                    continue;
+                }
+                Err(e) => Err(e)?,
+            };
+            for chunk in manifest.chunks {
+                garbage.remove(&chunk);
+            }
+            if garbage.is_empty() {
+                return Ok(());
+            }
+        }
+        for chunk in garbage {
+            self.ds.del(CHUNKS.to_string() + &chunk).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl DataStorage for Chunked {
+    async fn get(&self, name: String, path: Option<&Path>) -> Result<()> {
+        let manifest = self.manifest(&name).await?;
+        let path = path.unwrap_or(Path::new(&name));
+        let mut file = std::fs::File::create(path)
+            .map_err(|err| Error::FileError(format!("Failed to write local file: {err:?}")))?;
+        // each chunk is appended as it arrives, so the file is never held in memory
+        let mut size = 0;
+        for chunk in &manifest.chunks {
+            let tmp = TmpFile(tmp());
+            self.ds
+                .get(CHUNKS.to_string() + chunk, Some(&tmp.0))
+                .await
+                .with_context(|| format!("Failed to get chunk {chunk}"))?;
+            size += std::fs::File::open(&tmp.0)
+                .and_then(|mut x| std::io::copy(&mut x, &mut file))
+                .map_err(|err| Error::FileError(format!("Failed to write local file: {err:?}")))?;
+        }
+        if size != manifest.size {
+            Err(anyhow::anyhow!("Reassembled {name} has the wrong size"))?;
+        }
+        Ok(())
+    }
+    async fn put(&self, name: String, path: &Path) -> Result<String> {
+        let file = std::fs::File::open(path).map_err(|e| Error::FileError(e.to_string()))?;
+        let gc = self.gc.read().await;
+        let avg = self.config.avg_size;
+        let mut manifest = Manifest {
+            size: 0,
+            chunks: Vec::new(),
+        };
+        let mut stored = self
+            .ds
+            .list(Some(CHUNKS))
+            .await?
+            .into_iter()
+            .collect::<HashSet<_>>();
+        let mut uploaded = 0;
+        for chunk in StreamCDC::new(file, avg / 4, avg, avg * 4) {
+            let chunk = chunk.map_err(|e| Error::FileError(format!("{e:?}")))?;
+            let hash = Sha256::digest(&chunk.data)
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect::<String>();
+            let key = CHUNKS.to_string() + &hash;
+            if !stored.contains(&key) {
+                self.write(&key, &chunk.data)
+                    .await
+                    .with_context(|| format!("Failed to put chunk {hash}"))?;
+                stored.insert(key);
+                uploaded += 1;
+            }
+            manifest.size += chunk.length as u64;
+            manifest.chunks.push(hash);
+        }
+
+        // chunks only the previous version used are garbage afterwards
+        let previous = match self.exists(&name).await? {
+            true => self.manifest(&name).await.ok(),
+            false => None,
+        };
+        let data = serde_json::to_vec(&manifest).with_context(|| "Failed to serialize")?;
+        self.write(&(MANIFESTS.to_string() + &name), &data).await?;
+        drop(gc);
+        if let Some(previous) = previous {
+            self.collect(previous.chunks).await?;
+        }
+        Ok(format!(
+            "chunked: {} chunks, {uploaded} uploaded",
+            manifest.chunks.len()
+        ))
+    }
+    async fn del(&self, name: String) -> Result<()> {
+        let manifest = self.manifest(&name).await?;
+        self.ds.del(MANIFESTS.to_string() + &name).await?;
+        self.collect(manifest.chunks).await
+    }
+    async fn list(&self, prefix: Option<&str>) -> Result<Vec<String>> {
+        let prefix = MANIFESTS.to_string() + prefix.unwrap_or_default();
+        let names = self.ds.list(Some(&prefix)).await?;
+        Ok(names
+            .into_iter()
+            .filter_map(|x| x.strip_prefix(MANIFESTS).map(|x| x.to_string()))
+            .collect())
+    }
+    async fn health_check(&self) -> Vec<Check> {
+        self.ds.health_check().await
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rm::ds::tests::{noise, Memory};
+
+    fn chunked(memory: &Memory) -> Chunked {
+        let config = ChunkConfig {
+            ds: "memory".to_string(),
+            avg_size: AVERAGE_MIN,
+        };
+        Chunked::new(config, SafeDs::new(Box::new(memory.clone()))).unwrap()
+    }
+
+    fn chunks(memory: &Memory) -> HashSet<String> {
+        let objects = memory.objects.lock().unwrap();
+        objects
+            .keys()
+            .filter(|x| x.starts_with(CHUNKS))
+            .cloned()
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn versions_share_chunks() {
+        let memory = Memory::default();
+        let ds = chunked(&memory);
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let (path, out) = (dir.join("file"), dir.join("out"));
+
+        let mut data = noise(64 << 10, 1);
+        std::fs::write(&path, &data).unwrap();
+        ds.put("file".to_string(), &path).await.unwrap();
+        let first = chunks(&memory);
+        // a change in the middle only touches the chunks around it
+        data[32 << 10] ^= 0xff;
+        std::fs::write(&path, &data).unwrap();
+        let desc = ds.put("file".to_string(), &path).await.unwrap();
+        let second = chunks(&memory);
+        assert!(
+            first.intersection(&second).count() > first.len() / 2,
+            "{desc}"
+        );
+        assert!(
+            !desc.ends_with(&format!(" {} uploaded", second.len())),
+            "{desc}"
+        );
+
+        ds.get("file".to_string(), Some(&out)).await.unwrap();
+        assert_eq!(std::fs::read(&out).unwrap(), data);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn unused_chunks_are_collected() {
+        let memory = Memory::default();
+        let ds = chunked(&memory);
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let (a, b, out) = (dir.join("a"), dir.join("b"), dir.join("out"));
+
+        // b shares the first half of a
+        let data = noise(64 << 10, 2);
+        std::fs::write(&a, &data).unwrap();
+        std::fs::write(&b, &data[..32 << 10]).unwrap();
+        ds.put("a".to_string(), &a).await.unwrap();
+        let only_a = chunks(&memory);
+        ds.put("b".to_string(), &b).await.unwrap();
+        let of_b = chunks(&memory)
+            .into_iter()
+            .filter(|x| !only_a.contains(x))
+            .collect::<HashSet<_>>();
+
+        // a new version of a drops the chunks of the old one
+        std::fs::write(&a, noise(16 << 10, 3)).unwrap();
+        ds.put("a".to_string(), &a).await.unwrap();
+        ds.del("a".to_string()).await.unwrap();
+        let left = chunks(&memory);
+        assert!(left.is_superset(&of_b));
+        let manifest = ds.manifest("b").await.unwrap();
+        assert_eq!(
+            left,
+            manifest
+                .chunks
+                .iter()
+                .map(|x| CHUNKS.to_string() + x)
+                .collect::<HashSet<_>>()
+        );
+        ds.get("b".to_string(), Some(&out)).await.unwrap();
+        assert_eq!(std::fs::read(&out).unwrap(), &data[..32 << 10]);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
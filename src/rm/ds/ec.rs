@@ -0,0 +1,334 @@
+use std::{
+    any::Any,
+    collections::BTreeSet,
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+use crate::error::Error;
+
+use super::{tmp, Check, DataStorage, SafeDs, TmpFile};
+
+/// Bytes before the payload of every shard, holding the length of the file
+const HEADER: usize = 8;
+/// Bytes of each shard encoded or reconstructed at once
+const STRIPE: usize = 1 << 20;
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct ErasureConfig {
+    /// Number of data shards
+    pub data: usize,
+    /// Number of parity shards, i.e. how many shards may be lost
+    pub parity: usize,
+    /// Datastore of each shard, data shards first
+    pub ds: Vec<String>,
+}
+
+/// Splits files into Reed-Solomon shards over other storages
+///
+/// Shard `i` of file `name` is stored as `name.i` in the `i`th storage.
+pub struct Erasure {
+    config: ErasureConfig,
+    codec: ReedSolomon,
+    shards: Vec<SafeDs>,
+}
+
+impl Erasure {
+    pub fn new(config: ErasureConfig, shards: Vec<SafeDs>) -> Result<Self> {
+        if shards.len() != config.data + config.parity {
+            Err(anyhow::anyhow!(
+                "Expected {} datastores, got {}",
+                config.data + config.parity,
+                shards.len()
+            ))?;
+        }
+        let codec = ReedSolomon::new(config.data, config.parity)
+            .map_err(|e| anyhow::anyhow!("Invalid shard counts: {e:?}"))?;
+        Ok(Self {
+            config,
+            codec,
+            shards,
+        })
+    }
+
+    fn shard_name(name: &str, i: usize) -> String {
+        format!("{name}.{i}")
+    }
+
+    /// Payload bytes of each shard of a file of `len` bytes
+    fn shard_size(&self, len: u64) -> usize {
+        (len as usize).div_ceil(self.config.data).max(1)
+    }
+}
+
+#[async_trait::async_trait]
+impl DataStorage for Erasure {
+    async fn get(&self, name: String, path: Option<&Path>) -> Result<()> {
+        let tmps = self
+            .shards
+            .iter()
+            .map(|_| TmpFile(tmp()))
+            .collect::<Vec<_>>();
+        let gets = self
+            .shards
+            .iter()
+            .zip(&tmps)
+            .enumerate()
+            .map(|(i, (shard, tmp))| shard.get(Self::shard_name(&name, i), Some(&tmp.0)));
+        let results = futures::future::join_all(gets).await;
+        let mut files = Vec::new();
+        let mut len = None;
+        for (i, (res, tmp)) in results.into_iter().zip(&tmps).enumerate() {
+            let file = res.and_then(|_| {
+                let mut file = File::open(&tmp.0)?;
+                let mut header = [0; HEADER];
+                file.read_exact(&mut header)?;
+                let shard_len = u64::from_le_bytes(header);
+                let size = self.shard_size(shard_len);
+                // a shard of another length belongs to another version
+                if file.metadata()?.len() != (HEADER + size) as u64
+                    || len.is_some_and(|x| x != shard_len)
+                {
+                    Err(anyhow::anyhow!("Malformed shard"))?;
+                }
+                len = Some(shard_len);
+                Ok(file)
+            });
+            files.push(match file {
+                Ok(file) => Some(file),
+                Err(e) => {
+                    tracing::warn!("Failed to get shard {i} of {name}: {e:#}");
+                    None
+                }
+            });
+        }
+        let present = files.iter().flatten().count();
+        if present < self.config.data {
+            Err(Error::NotFound(format!(
+                "Only {present} of {} shards of {name} left",
+                self.config.data
+            )))?;
+        }
+        let len = len.unwrap();
+        let size = self.shard_size(len);
+
+        // the data shards are the consecutive parts of the file, rebuilt a
+        // stripe at a time so that the file is never held in memory
+        let io =
+            |err: std::io::Error| Error::FileError(format!("Failed to write local file: {err:?}"));
+        let mut out = File::create(path.unwrap_or(Path::new(&name))).map_err(io)?;
+        out.set_len(len).map_err(io)?;
+        let mut offset = 0;
+        while offset < size {
+            let block = STRIPE.min(size - offset);
+            let mut stripe = Vec::new();
+            for (i, file) in files.iter_mut().enumerate() {
+                let mut buf = vec![0; block];
+                let read = file.as_mut().map(|x| x.read_exact(&mut buf));
+                stripe.push(match read {
+                    Some(Ok(())) => Some(buf),
+                    Some(Err(e)) => {
+                        tracing::warn!("Failed to read shard {i} of {name}: {e}");
+                        *file = None;
+                        None
+                    }
+                    None => None,
+                });
+            }
+            self.codec
+                .reconstruct_data(&mut stripe)
+                .map_err(|e| anyhow::anyhow!("Failed to reconstruct {name}: {e:?}"))?;
+            for (k, shard) in stripe.into_iter().take(self.config.data).enumerate() {
+                let at = (k * size + offset) as u64;
+                if at >= len {
+                    break;
+                }
+                let shard = shard.unwrap();
+                let end = shard.len().min((len - at) as usize);
+                out.seek(SeekFrom::Start(at))
+                    .and_then(|_| out.write_all(&shard[..end]))
+                    .map_err(io)?;
+            }
+            offset += block;
+        }
+        Ok(())
+    }
+    async fn put(&self, name: String, path: &Path) -> Result<String> {
+        let io = |e: std::io::Error| Error::FileError(e.to_string());
+        let mut file = File::open(path).map_err(io)?;
+        let len = file.metadata().map_err(io)?.len();
+        let size = self.shard_size(len);
+        let tmps = self
+            .shards
+            .iter()
+            .map(|_| TmpFile(tmp()))
+            .collect::<Vec<_>>();
+        let mut outs = tmps
+            .iter()
+            .map(|x| File::create(&x.0))
+            .collect::<std::io::Result<Vec<_>>>()
+            .map_err(io)?;
+        for out in &mut outs {
+            out.write_all(&len.to_le_bytes()).map_err(io)?;
+        }
+        // encoded a stripe at a time, the same as encoding whole shards
+        let mut offset = 0;
+        while offset < size {
+            let block = STRIPE.min(size - offset);
+            let mut stripe = vec![vec![0u8; block]; self.shards.len()];
+            for (k, shard) in stripe.iter_mut().take(self.config.data).enumerate() {
+                let at = (k * size + offset) as u64;
+                if at < len {
+                    let end = block.min((len - at) as usize);
+                    file.seek(SeekFrom::Start(at))
+                        .and_then(|_| file.read_exact(&mut shard[..end]))
+                        .map_err(io)?;
+                }
+            }
+            self.codec
+                .encode(&mut stripe)
+                .map_err(|e| anyhow::anyhow!("Failed to encode {name}: {e:?}"))?;
+            for (out, shard) in outs.iter_mut().zip(&stripe) {
+                out.write_all(shard).map_err(io)?;
+            }
+            offset += block;
+        }
+        drop(outs);
+
+        let puts = tmps.iter().enumerate().map(|(i, tmp)| {
+            let name = &name;
+            async move {
+                self.shards[i]
+                    .put(Self::shard_name(name, i), &tmp.0)
+                    .await
+                    .with_context(|| format!("Failed to put shard {i}"))?;
+                Ok::<_, anyhow::Error>(format!(
+                    "{}:{}",
+                    self.config.ds[i],
+                    Self::shard_name(name, i)
+                ))
+            }
+        });
+        let results = futures::future::join_all(puts).await;
+        if results.iter().any(|x| x.is_err()) {
+            // the shards written are of no use without the others
+            for (i, res) in results.iter().enumerate() {
+                if res.is_err() {
+                    continue;
+                }
+                if let Err(e) = self.shards[i].del(Self::shard_name(&name, i)).await {
+                    tracing::warn!("Failed to delete shard {i} of {name}: {e:#}");
+                }
+            }
+        }
+        let placement = results.into_iter().collect::<Result<Vec<_>>>()?;
+        Ok(format!(
+            "ec({}+{}) {}",
+            self.config.data,
+            self.config.parity,
+            placement.join(" ")
+        ))
+    }
+    async fn del(&self, name: String) -> Result<()> {
+        let mut err = None;
+        for (i, shard) in self.shards.iter().enumerate() {
+            if let Err(e) = shard.del(Self::shard_name(&name, i)).await {
+                err = Some(e.context(format!("Failed to delete shard {i}")));
+            }
+        }
+        err.map_or(Ok(()), Err)
+    }
+    async fn list(&self, prefix: Option<&str>) -> Result<Vec<String>> {
+        let mut names = BTreeSet::new();
+        for (i, shard) in self.shards.iter().enumerate() {
+            let suffix = format!(".{i}");
+            names.extend(
+                shard
+                    .list(prefix)
+                    .await?
+                    .into_iter()
+                    .filter_map(|x| x.strip_suffix(&suffix).map(|x| x.to_string())),
+            );
+        }
+        Ok(names.into_iter().collect())
+    }
+    async fn health_check(&self) -> Vec<Check> {
+        let mut checks = Vec::new();
+        for (ds, shard) in self.config.ds.iter().zip(&self.shards) {
+            checks.extend(shard.health_check().await.into_iter().map(|x| Check {
+                op: format!("{ds}:{}", x.op),
+                ..x
+            }));
+        }
+        checks
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rm::ds::tests::{noise, Memory};
+
+    /// Three data and two parity shards over the storages
+    fn erasure(memories: &[Memory]) -> Erasure {
+        let config = ErasureConfig {
+            data: 3,
+            parity: 2,
+            ds: (0..memories.len()).map(|i| i.to_string()).collect(),
+        };
+        let shards = memories
+            .iter()
+            .map(|x| SafeDs::new(Box::new(x.clone())))
+            .collect();
+        Erasure::new(config, shards).unwrap()
+    }
+
+    #[tokio::test]
+    async fn reconstructs_without_as_many_shards_as_parity() {
+        let memories = (0..5).map(|_| Memory::default()).collect::<Vec<_>>();
+        let ds = erasure(&memories);
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let (path, out) = (dir.join("file"), dir.join("out"));
+
+        // more than a stripe per shard, not a multiple of the data shards
+        let data = noise(3 * STRIPE + 1000, 4);
+        std::fs::write(&path, &data).unwrap();
+        ds.put("file".to_string(), &path).await.unwrap();
+        for i in [0, 3] {
+            memories[i].objects.lock().unwrap().clear();
+        }
+        ds.get("file".to_string(), Some(&out)).await.unwrap();
+        assert_eq!(std::fs::read(&out).unwrap(), data);
+
+        // one more shard lost is one too many
+        memories[1].objects.lock().unwrap().clear();
+        let err = ds.get("file".to_string(), Some(&out)).await.err().unwrap();
+        assert!(
+            matches!(err.downcast_ref(), Some(Error::NotFound(_))),
+            "{err:#}"
+        );
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn failed_put_deletes_shards() {
+        let mut memories = (0..5).map(|_| Memory::default()).collect::<Vec<_>>();
+        memories[4].broken = true;
+        let ds = erasure(&memories);
+        let path = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::write(&path, noise(1000, 5)).unwrap();
+        assert!(ds.put("file".to_string(), &path).await.is_err());
+        for memory in &memories {
+            assert!(memory.objects.lock().unwrap().is_empty());
+        }
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,231 @@
+use std::{any::Any, collections::BTreeMap, path::Path};
+
+use anyhow::{Context, Result};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_LENGTH},
+    StatusCode,
+};
+
+use crate::error::Error;
+
+use super::{
+    contained,
+    s3::encode_key,
+    stream::{download, upload},
+    Check, DataStorage, Observer,
+};
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct HttpConfig {
+    /// URL the object names are appended to
+    pub url: String,
+    /// Whether the server accepts PUT and DELETE of objects
+    #[serde(default)]
+    pub writable: bool,
+    /// Headers sent with every request
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Token of bearer authentication
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+/// Objects served by a plain HTTP server, which cannot be listed
+pub struct Http {
+    client: reqwest::Client,
+    config: HttpConfig,
+}
+
+impl Http {
+    pub fn new(config: HttpConfig) -> Result<Self> {
+        let mut headers = HeaderMap::new();
+        for (key, value) in &config.headers {
+            headers.insert(
+                HeaderName::from_bytes(key.as_bytes())
+                    .with_context(|| format!("Invalid header name {key}"))?,
+                HeaderValue::from_str(value)
+                    .with_context(|| format!("Invalid value of header {key}"))?,
+            );
+        }
+        if let Some(token) = &config.token {
+            let mut value = HeaderValue::from_str(&format!("Bearer {token}"))
+                .with_context(|| "Invalid bearer token")?;
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .build()
+            .with_context(|| "Failed to build HTTP client")?;
+        Ok(Self { client, config })
+    }
+
+    /// URL of an object, which must stay below the base URL
+    fn url(&self, name: &str) -> Result<String> {
+        if !contained(name) {
+            Err(anyhow::anyhow!("Invalid object name {name:?}"))?;
+        }
+        Ok(format!(
+            "{}/{}",
+            self.config.url.trim_end_matches('/'),
+            encode_key(name)
+        ))
+    }
+
+    fn writable(&self, op: &str) -> Result<()> {
+        if !self.config.writable {
+            Err(Error::Unsupported(format!(
+                "{op} on read-only HTTP storage {}",
+                self.config.url
+            )))?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl DataStorage for Http {
+    async fn get(&self, name: String, path: Option<&Path>) -> Result<()> {
+        self.get_with_progress(name, path, &|_| {}).await
+    }
+    async fn put(&self, name: String, path: &Path) -> Result<String> {
+        self.put_with_progress(name, path, &|_| {}).await
+    }
+    async fn get_with_progress(
+        &self,
+        name: String,
+        path: Option<&Path>,
+        observer: &Observer,
+    ) -> Result<()> {
+        download(self.client.get(self.url(&name)?), &name, path, observer)
+            .await
+            .with_context(|| "Failed to get object from HTTP")
+    }
+    async fn put_with_progress(
+        &self,
+        name: String,
+        path: &Path,
+        observer: &Observer,
+    ) -> Result<String> {
+        self.writable("put")?;
+        let url = self.url(&name)?;
+        upload(self.client.put(&url), &name, path, observer)
+            .await
+            .with_context(|| "Failed to put object to HTTP")?;
+        Ok(url)
+    }
+    async fn del(&self, name: String) -> Result<()> {
+        self.writable("delete")?;
+        self.client
+            .delete(self.url(&name)?)
+            .send()
+            .await
+            .and_then(|x| x.error_for_status())
+            .with_context(|| "Failed to delete object from HTTP")?;
+        Ok(())
+    }
+    async fn stat(&self, name: String) -> Result<Option<u64>> {
+        let res = self
+            .client
+            .head(self.url(&name)?)
+            .send()
+            .await
+            .with_context(|| "Failed to find object on HTTP")?;
+        if res.status() == StatusCode::NOT_FOUND {
+            Err(Error::NotFound(format!("Object {name} not found")))?;
+        }
+        let res = res
+            .error_for_status()
+            .with_context(|| "Failed to find object on HTTP")?;
+        // the body of a response to HEAD is empty, whatever its length says
+        Ok(res
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.parse().ok()))
+    }
+    async fn list(&self, _prefix: Option<&str>) -> Result<Vec<String>> {
+        Err(Error::Unsupported(format!(
+            "list of HTTP storage {}",
+            self.config.url
+        )))?
+    }
+    async fn health_check(&self) -> Vec<Check> {
+        let mut checks = Vec::new();
+
+        let (check, _) = Check::run("server", async {
+            let res = self
+                .client
+                .head(&self.config.url)
+                .send()
+                .await
+                .with_context(|| "Failed to reach server")?;
+            // the URL itself need not be an object, but it must be served and
+            // the credentials accepted
+            let status = res.status();
+            let denied = [
+                StatusCode::UNAUTHORIZED,
+                StatusCode::FORBIDDEN,
+                StatusCode::PROXY_AUTHENTICATION_REQUIRED,
+            ];
+            if status.is_server_error() || denied.contains(&status) {
+                Err(anyhow::anyhow!("Server responded {status}"))?;
+            }
+            Ok(())
+        })
+        .await;
+        checks.push(check);
+        if !self.config.writable {
+            return checks;
+        }
+
+        let url = format!(
+            "{}/.easy-fm-probe-{}",
+            self.config.url.trim_end_matches('/'),
+            uuid::Uuid::new_v4()
+        );
+        let (check, written) = Check::run("write", async {
+            self.client
+                .put(&url)
+                .body("easy-fm")
+                .send()
+                .await
+                .and_then(|x| x.error_for_status())
+                .with_context(|| "Failed to put probe object")
+        })
+        .await;
+        checks.push(check);
+        if written.is_none() {
+            return checks;
+        }
+
+        let (check, _) = Check::run("read", async {
+            self.client
+                .get(&url)
+                .send()
+                .await
+                .and_then(|x| x.error_for_status())
+                .with_context(|| "Failed to get probe object")?
+                .bytes()
+                .await
+                .with_context(|| "Failed to read probe object")
+        })
+        .await;
+        checks.push(check);
+
+        let (check, _) = Check::run("delete", async {
+            self.client
+                .delete(&url)
+                .send()
+                .await
+                .and_then(|x| x.error_for_status())
+                .with_context(|| "Failed to delete probe object")
+        })
+        .await;
+        checks.push(check);
+        checks
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
@@ -0,0 +1,162 @@
+use std::{
+    any::Any,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+
+use super::{Check, DataStorage, Observer, SafeDs};
+
+/// A bandwidth limit in bytes per second, shared by the transfers it applies to
+pub struct Limiter {
+    rate: u64,
+    /// When the bytes taken so far are paid for
+    next: Mutex<Instant>,
+}
+
+impl Limiter {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate: rate.max(1),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// Take `bytes` from the limit, returning when they are paid for
+    fn reserve(&self, bytes: u64) -> Instant {
+        let mut next = self.next.lock().unwrap();
+        *next =
+            (*next).max(Instant::now()) + Duration::from_secs_f64(bytes as f64 / self.rate as f64);
+        *next
+    }
+}
+
+tokio::task_local! {
+    static LIMITERS: Vec<Arc<Limiter>>;
+}
+
+/// Wait for `bytes` to fit in the limits of the current transfer
+///
+/// Storages call this for the bytes they stream, before sending or after
+/// receiving them.
+pub async fn throttle(bytes: u64) {
+    let until = LIMITERS
+        .try_with(|limiters| limiters.iter().map(|x| x.reserve(bytes)).max())
+        .ok()
+        .flatten();
+    if let Some(until) = until {
+        tokio::time::sleep_until(until.into()).await;
+    }
+}
+
+/// A storage whose transfers are throttled by limiters
+pub struct Limited {
+    inner: SafeDs,
+    limiters: Vec<Arc<Limiter>>,
+}
+
+impl Limited {
+    pub fn new(inner: SafeDs, limiters: Vec<Arc<Limiter>>) -> Self {
+        Self { inner, limiters }
+    }
+
+    /// The limiters of the enclosing transfer and these ones, so that a copy
+    /// to a limited storage stays within the limits of both storages
+    fn limiters(&self) -> Vec<Arc<Limiter>> {
+        let mut limiters = LIMITERS.try_with(|x| x.clone()).unwrap_or_default();
+        for limiter in &self.limiters {
+            if !limiters.iter().any(|x| Arc::ptr_eq(x, limiter)) {
+                limiters.push(limiter.clone());
+            }
+        }
+        limiters
+    }
+}
+
+#[async_trait::async_trait]
+impl DataStorage for Limited {
+    async fn get(&self, name: String, path: Option<&Path>) -> Result<()> {
+        LIMITERS
+            .scope(self.limiters(), self.inner.get(name, path))
+            .await
+    }
+    async fn put(&self, name: String, path: &Path) -> Result<String> {
+        LIMITERS
+            .scope(self.limiters(), self.inner.put(name, path))
+            .await
+    }
+    async fn get_with_progress(
+        &self,
+        name: String,
+        path: Option<&Path>,
+        observer: &Observer,
+    ) -> Result<()> {
+        let get = self.inner.get_with_progress(name, path, observer);
+        LIMITERS.scope(self.limiters(), get).await
+    }
+    async fn put_with_progress(
+        &self,
+        name: String,
+        path: &Path,
+        observer: &Observer,
+    ) -> Result<String> {
+        let put = self.inner.put_with_progress(name, path, observer);
+        LIMITERS.scope(self.limiters(), put).await
+    }
+    async fn del(&self, name: String) -> Result<()> {
+        self.inner.del(name).await
+    }
+    async fn list(&self, prefix: Option<&str>) -> Result<Vec<String>> {
+        self.inner.list(prefix).await
+    }
+    async fn stat(&self, name: String) -> Result<Option<u64>> {
+        self.inner.stat(name).await
+    }
+    async fn copy(
+        &self,
+        name: String,
+        to: &(dyn DataStorage + Send + Sync),
+        raw: String,
+    ) -> Result<String> {
+        LIMITERS
+            .scope(self.limiters(), self.inner.copy(name, to, raw))
+            .await
+    }
+    async fn health_check(&self) -> Vec<Check> {
+        self.inner.health_check().await
+    }
+    fn as_any(&self) -> &dyn Any {
+        self.inner.as_any()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserve_accumulates() {
+        let limiter = Limiter::new(1000);
+        let start = Instant::now();
+        let first = limiter.reserve(500);
+        let second = limiter.reserve(1500);
+        // the second reservation queues behind the first one
+        assert!(first >= start + Duration::from_millis(500));
+        assert_eq!(second - first, Duration::from_millis(1500));
+        // an idle limiter does not bank the time it was unused for
+        let idle = Limiter::new(1000);
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(idle.reserve(0) >= Instant::now() - Duration::from_millis(5));
+    }
+
+    #[test]
+    fn zero_rate_is_one() {
+        assert_eq!(Limiter::new(0).rate(), 1);
+    }
+}
@@ -0,0 +1,347 @@
+use std::{
+    any::Any,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    io::ErrorKind,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+
+use super::{Check, DataStorage, Observer, SafeDs};
+
+/// How the operations of a data storage are retried
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Tries of each operation, the first one included
+    pub attempts: u32,
+    /// Delay before the first retry, doubling after each one
+    pub backoff_ms: u64,
+    /// Upper bound of the delay between tries
+    pub max_backoff_ms: u64,
+    /// Time limit of each try without progress, so that a transfer may take
+    /// longer as long as its bytes keep flowing
+    pub timeout_ms: Option<u64>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            backoff_ms: 500,
+            max_backoff_ms: 30_000,
+            timeout_ms: None,
+        }
+    }
+}
+
+/// A random delay between half and all of `delay`, so that clients failing
+/// together do not retry together
+fn jitter(delay: Duration) -> Duration {
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    let half = delay.as_millis() as u64 / 2;
+    Duration::from_millis(half + random % (half + 1))
+}
+
+/// Marks a failure the storage reported as worth retrying, e.g. S3 asking to
+/// slow down
+#[derive(Debug, thiserror::Error)]
+#[error("Transient failure")]
+pub(super) struct Transient;
+
+/// Whether `e` may go away by itself: timeouts, connection errors, errors of
+/// the server and throttling, but neither missing objects nor denied access
+fn transient(e: &anyhow::Error) -> bool {
+    if e.downcast_ref::<Transient>().is_some() {
+        return true;
+    }
+    e.chain().any(|cause| {
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            return e.is_timeout()
+                || e.is_connect()
+                || e.is_request()
+                || e.is_body()
+                || e.status().is_some_and(|x| {
+                    x.is_server_error() || x == reqwest::StatusCode::TOO_MANY_REQUESTS
+                });
+        }
+        if let Some(e) = cause.downcast_ref::<aws_sdk_s3::error::ConnectorError>() {
+            return e.is_timeout() || e.is_io();
+        }
+        if let Some(e) = cause.downcast_ref::<ssh2::Error>() {
+            // LIBSSH2_ERROR_SOCKET_SEND, _TIMEOUT, _SOCKET_DISCONNECT,
+            // _SOCKET_TIMEOUT and _SOCKET_RECV
+            return matches!(
+                e.code(),
+                ssh2::ErrorCode::Session(-7 | -9 | -13 | -30 | -43)
+            );
+        }
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            return matches!(
+                e.kind(),
+                ErrorKind::TimedOut
+                    | ErrorKind::ConnectionRefused
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::NotConnected
+                    | ErrorKind::BrokenPipe
+                    | ErrorKind::UnexpectedEof
+                    | ErrorKind::Interrupted
+            );
+        }
+        false
+    })
+}
+
+/// When a try last made progress
+struct Idle(Mutex<Instant>);
+
+impl Idle {
+    /// Return once there was no progress for `timeout`
+    async fn exceeds(&self, timeout: Duration) {
+        loop {
+            let until = *self.0.lock().unwrap() + timeout;
+            if Instant::now() >= until {
+                return;
+            }
+            tokio::time::sleep_until(until.into()).await;
+        }
+    }
+}
+
+tokio::task_local! {
+    static IDLE: Arc<Idle>;
+}
+
+/// Note that the current transfer made progress, keeping it from timing out
+pub(super) fn progressed() {
+    let _ = IDLE.try_with(|idle| *idle.0.lock().unwrap() = Instant::now());
+}
+
+/// A storage retrying the failed operations of another one
+pub struct Retrying {
+    inner: SafeDs,
+    policy: RetryPolicy,
+}
+
+impl Retrying {
+    pub fn new(inner: SafeDs, policy: RetryPolicy) -> Self {
+        Self { inner, policy }
+    }
+
+    async fn retry<T, F, Fut>(&self, op: &str, f: F) -> Result<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut delay = Duration::from_millis(self.policy.backoff_ms);
+        let max = Duration::from_millis(self.policy.max_backoff_ms);
+        let mut attempt = 1;
+        loop {
+            let res = match self.policy.timeout_ms.map(Duration::from_millis) {
+                Some(timeout) => {
+                    let idle = Arc::new(Idle(Mutex::new(Instant::now())));
+                    tokio::select! {
+                        res = IDLE.scope(idle.clone(), f()) => res,
+                        _ = idle.exceeds(timeout) => Err(anyhow::Error::new(std::io::Error::new(
+                            ErrorKind::TimedOut,
+                            format!("No progress for {timeout:?}"),
+                        ))),
+                    }
+                }
+                None => f().await,
+            };
+            match res {
+                Err(e) if attempt < self.policy.attempts && transient(&e) => {
+                    let wait = jitter(delay);
+                    tracing::warn!("Failed to {op}, retrying in {wait:?}: {e:#}");
+                    tokio::time::sleep(wait).await;
+                    delay = (delay * 2).min(max);
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl DataStorage for Retrying {
+    async fn get(&self, name: String, path: Option<&Path>) -> Result<()> {
+        self.retry("get", || self.inner.get(name.clone(), path))
+            .await
+    }
+    async fn put(&self, name: String, path: &Path) -> Result<String> {
+        self.retry("put", || self.inner.put(name.clone(), path))
+            .await
+    }
+    async fn get_with_progress(
+        &self,
+        name: String,
+        path: Option<&Path>,
+        observer: &Observer,
+    ) -> Result<()> {
+        self.retry("get", || {
+            self.inner.get_with_progress(name.clone(), path, observer)
+        })
+        .await
+    }
+    async fn put_with_progress(
+        &self,
+        name: String,
+        path: &Path,
+        observer: &Observer,
+    ) -> Result<String> {
+        self.retry("put", || {
+            self.inner.put_with_progress(name.clone(), path, observer)
+        })
+        .await
+    }
+    async fn del(&self, name: String) -> Result<()> {
+        self.retry("del", || self.inner.del(name.clone())).await
+    }
+    async fn list(&self, prefix: Option<&str>) -> Result<Vec<String>> {
+        self.retry("list", || self.inner.list(prefix)).await
+    }
+    async fn stat(&self, name: String) -> Result<Option<u64>> {
+        self.retry("stat", || self.inner.stat(name.clone())).await
+    }
+    async fn copy(
+        &self,
+        name: String,
+        to: &(dyn DataStorage + Send + Sync),
+        raw: String,
+    ) -> Result<String> {
+        self.retry("copy", || self.inner.copy(name.clone(), to, raw.clone()))
+            .await
+    }
+    async fn health_check(&self) -> Vec<Check> {
+        self.inner.health_check().await
+    }
+    // transparent, so that storages still recognize each other for copies
+    fn as_any(&self) -> &dyn Any {
+        self.inner.as_any()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+    use crate::{error::Error, rm::ds::Meter};
+
+    /// A storage whose gets fail with `fail` before the third try
+    struct Flaky {
+        tries: Arc<AtomicU32>,
+        fail: fn() -> anyhow::Error,
+        /// Time each get stalls before its progress
+        stall: Duration,
+        /// Progress reported by each get, 10 ms apart
+        steps: u32,
+    }
+
+    #[async_trait::async_trait]
+    impl DataStorage for Flaky {
+        async fn get(&self, _name: String, _path: Option<&Path>) -> Result<()> {
+            let tried = self.tries.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(self.stall).await;
+            let mut meter = Meter::new(&|_| {}, "flaky", None);
+            for _ in 0..self.steps {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                meter.add(1);
+            }
+            match tried < 2 {
+                true => Err((self.fail)()),
+                false => Ok(()),
+            }
+        }
+        async fn put(&self, _name: String, _path: &Path) -> Result<String> {
+            unimplemented!()
+        }
+        async fn del(&self, _name: String) -> Result<()> {
+            unimplemented!()
+        }
+        async fn list(&self, _prefix: Option<&str>) -> Result<Vec<String>> {
+            unimplemented!()
+        }
+        async fn health_check(&self) -> Vec<Check> {
+            Vec::new()
+        }
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    /// Tries of a get of `flaky`, and whether it succeeded
+    async fn tries(flaky: Flaky, timeout_ms: Option<u64>) -> (u32, bool) {
+        let tries = flaky.tries.clone();
+        let policy = RetryPolicy {
+            attempts: 3,
+            backoff_ms: 1,
+            max_backoff_ms: 2,
+            timeout_ms,
+        };
+        let res = Retrying::new(SafeDs::new(Box::new(flaky)), policy)
+            .get("x".to_string(), None)
+            .await;
+        (tries.load(Ordering::SeqCst), res.is_ok())
+    }
+
+    fn flaky(fail: fn() -> anyhow::Error) -> Flaky {
+        Flaky {
+            tries: Arc::new(AtomicU32::new(0)),
+            fail,
+            stall: Duration::ZERO,
+            steps: 0,
+        }
+    }
+
+    #[test]
+    fn jitter_is_bounded() {
+        for ms in [0, 1, 7, 500, 30_000] {
+            let delay = Duration::from_millis(ms);
+            for _ in 0..100 {
+                let wait = jitter(delay);
+                assert!(wait >= Duration::from_millis(ms / 2) && wait <= delay);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_transient_errors_only() {
+        let reset = || std::io::Error::from(ErrorKind::ConnectionReset).into();
+        assert_eq!(tries(flaky(reset), None).await, (3, true));
+        let throttled = || anyhow::anyhow!("SlowDown").context(Transient);
+        assert_eq!(tries(flaky(throttled), None).await, (3, true));
+
+        let missing = || Error::NotFound("x".to_string()).into();
+        assert_eq!(tries(flaky(missing), None).await, (1, false));
+        let denied = || anyhow::anyhow!("403 Forbidden");
+        assert_eq!(tries(flaky(denied), None).await, (1, false));
+        let local = || std::io::Error::from(ErrorKind::PermissionDenied).into();
+        assert_eq!(tries(flaky(local), None).await, (1, false));
+    }
+
+    #[tokio::test]
+    async fn times_out_without_progress() {
+        let reset = || std::io::Error::from(ErrorKind::ConnectionReset).into();
+        // progress every 10 ms keeps a get of 100 ms from timing out after 50
+        let slow = Flaky {
+            steps: 10,
+            ..flaky(reset)
+        };
+        assert_eq!(tries(slow, Some(50)).await, (3, true));
+        // a stalled get times out, and the timeout is retried
+        let stalled = Flaky {
+            stall: Duration::from_millis(200),
+            ..flaky(reset)
+        };
+        assert_eq!(tries(stalled, Some(50)).await, (3, false));
+    }
+}
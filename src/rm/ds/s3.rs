@@ -1,10 +1,51 @@
-use std::{fs::File, io::Write, path::Path};
+use std::{
+    any::Any,
+    fs::File,
+    io::{Read, Write},
+    path::Path,
+    pin::Pin,
+    task::{Context as TaskContext, Poll},
+};
 
 use anyhow::{Context, Result};
+use aws_sdk_s3::{
+    config::http::HttpResponse,
+    error::{ProvideErrorMetadata, SdkError},
+};
 
 use crate::error::Error;
 
-use super::DataStorage;
+use super::{retry::Transient, throttle, Check, DataStorage, Meter, Observer};
+
+/// Bytes read from a local file per chunk of an upload
+const CHUNK: usize = 1 << 20;
+
+/// A request body of `len` bytes, fed through a channel
+///
+/// The SDK sends the body from its own task, so that the chunks are throttled
+/// and metered as they are fed.
+struct Fed {
+    rx: tokio::sync::mpsc::Receiver<bytes::Bytes>,
+    len: u64,
+}
+
+impl http_body::Body for Fed {
+    type Data = bytes::Bytes;
+    type Error = std::io::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Option<std::result::Result<http_body::Frame<Self::Data>, Self::Error>>> {
+        self.rx
+            .poll_recv(cx)
+            .map(|x| x.map(|x| Ok(http_body::Frame::data(x))))
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        http_body::SizeHint::with_exact(self.len)
+    }
+}
 
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 pub struct S3config {
@@ -41,10 +82,70 @@ impl S3 {
             config: backup,
         }
     }
+
+    fn link(&self, name: &str) -> String {
+        self.config.endpoint.clone() + "/" + self.config.bucket.as_str() + "/" + name
+    }
+
+    /// Whether objects of `other` can be copied by this client on the server side
+    fn same_endpoint(&self, other: &S3) -> bool {
+        self.config.endpoint == other.config.endpoint
+            && self.config.access_key == other.config.access_key
+    }
+}
+
+/// Encode the key for `x-amz-copy-source`, or any URL path, keeping the path separators
+pub(super) fn encode_key(key: &str) -> String {
+    key.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
 }
+
+/// Mark the failures of a request worth retrying, which S3 only tells apart by
+/// their code or status
+fn classify<E>(e: SdkError<E, HttpResponse>) -> anyhow::Error
+where
+    E: ProvideErrorMetadata + std::error::Error + Send + Sync + 'static,
+{
+    let transient = match &e {
+        SdkError::TimeoutError(_) | SdkError::ResponseError(_) => true,
+        SdkError::DispatchFailure(x) => x.is_io() || x.is_timeout(),
+        SdkError::ServiceError(x) => {
+            let status = x.raw().status();
+            status.is_server_error()
+                || status.as_u16() == 429
+                || matches!(
+                    x.err().code(),
+                    Some("SlowDown" | "Throttling" | "RequestTimeout" | "InternalError")
+                )
+        }
+        _ => false,
+    };
+    match transient {
+        true => anyhow::Error::new(e).context(Transient),
+        false => anyhow::Error::new(e),
+    }
+}
+
 #[async_trait::async_trait]
 impl DataStorage for S3 {
     async fn get(&self, name: String, path: Option<&Path>) -> Result<()> {
+        self.get_with_progress(name, path, &|_| {}).await
+    }
+    async fn put(&self, name: String, path: &Path) -> Result<String> {
+        self.put_with_progress(name, path, &|_| {}).await
+    }
+    async fn get_with_progress(
+        &self,
+        name: String,
+        path: Option<&Path>,
+        observer: &Observer,
+    ) -> Result<()> {
         let mut file = File::create(path.unwrap_or(Path::new(&name)))
             .map_err(|err| Error::FileError(format!("Failed to create local file: {err:?}")))?;
 
@@ -52,35 +153,71 @@ impl DataStorage for S3 {
             .client
             .get_object()
             .bucket(self.config.bucket.clone())
-            .key(name)
+            .key(&name)
             .send()
             .await
+            .map_err(classify)
             .with_context(|| "Failed to get object from S3")?;
+        let total = object.content_length().map(|x| x as u64);
+        let mut meter = Meter::new(observer, &name, total);
         while let Some(bytes) = object
             .body
             .try_next()
             .await
+            // the connection broke off
+            .map_err(|e| anyhow::Error::new(e).context(Transient))
             .with_context(|| "Failed to read from S3 download stream")?
         {
             file.write_all(&bytes)
                 .with_context(|| "Failed to write from S3 download stream to local file")?;
+            meter.add(bytes.len() as u64);
+            throttle(bytes.len() as u64).await;
         }
         Ok(())
     }
-    async fn put(&self, name: String, path: &Path) -> Result<String> {
-        let body = aws_sdk_s3::primitives::ByteStream::from_path(path)
-            .await
-            .map_err(|e| Error::FileError(e.to_string()))?;
-        let file_link =
-            self.config.endpoint.clone() + "/" + self.config.bucket.as_str() + "/" + name.as_str();
-        let _ = self
+    async fn put_with_progress(
+        &self,
+        name: String,
+        path: &Path,
+        observer: &Observer,
+    ) -> Result<String> {
+        let len = std::fs::metadata(path)
+            .map_err(|e| Error::FileError(e.to_string()))?
+            .len();
+        let mut file = File::open(path).map_err(|e| Error::FileError(e.to_string()))?;
+        let file_link = self.link(&name);
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
+        let body = aws_sdk_s3::primitives::ByteStream::from_body_1_x(Fed { rx, len });
+        let send = self
             .client
             .put_object()
             .bucket(self.config.bucket.clone())
-            .key(name)
+            .key(&name)
+            .content_length(len as i64)
             .body(body)
-            .send()
-            .await
+            .send();
+        let feed = async move {
+            let mut meter = Meter::new(observer, &name, Some(len));
+            loop {
+                let mut buf = vec![0; CHUNK];
+                let n = file
+                    .read(&mut buf)
+                    .map_err(|e| Error::FileError(e.to_string()))?;
+                if n == 0 {
+                    return Ok::<_, anyhow::Error>(());
+                }
+                buf.truncate(n);
+                throttle(n as u64).await;
+                // the receiver is gone when the request failed
+                if tx.send(bytes::Bytes::from(buf)).await.is_err() {
+                    return Ok(());
+                }
+                meter.add(n as u64);
+            }
+        };
+        let (res, fed) = tokio::join!(send, feed);
+        fed?;
+        res.map_err(classify)
             .with_context(|| "Failed to put object to S3")?;
         Ok(file_link)
     }
@@ -92,7 +229,115 @@ impl DataStorage for S3 {
             .key(name)
             .send()
             .await
+            .map_err(classify)
             .with_context(|| "Failed to delete object from S3")?;
         Ok(())
     }
+    async fn list(&self, prefix: Option<&str>) -> Result<Vec<String>> {
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(self.config.bucket.clone())
+            .set_prefix(prefix.map(|x| x.to_string()))
+            .into_paginator()
+            .send();
+        let mut names = Vec::new();
+        while let Some(page) = pages.next().await {
+            let page = page
+                .map_err(classify)
+                .with_context(|| "Failed to list objects from S3")?;
+            names.extend(
+                page.contents()
+                    .iter()
+                    .filter_map(|x| x.key().map(|x| x.to_string())),
+            );
+        }
+        Ok(names)
+    }
+    async fn copy(
+        &self,
+        name: String,
+        to: &(dyn DataStorage + Send + Sync),
+        raw: String,
+    ) -> Result<String> {
+        let target = match to.as_any().downcast_ref::<S3>() {
+            Some(target) if target.same_endpoint(self) => target,
+            _ => return super::relay(self, name, to, raw).await,
+        };
+        let _ = target
+            .client
+            .copy_object()
+            .bucket(target.config.bucket.clone())
+            .key(raw.clone())
+            .copy_source(format!("{}/{}", self.config.bucket, encode_key(&name)))
+            .send()
+            .await
+            .map_err(classify)
+            .with_context(|| "Failed to copy object in S3")?;
+        Ok(target.link(&raw))
+    }
+    async fn health_check(&self) -> Vec<Check> {
+        let bucket = self.config.bucket.clone();
+        let key = format!(".easy-fm-probe-{}", uuid::Uuid::new_v4());
+        let mut checks = Vec::new();
+
+        let (check, _) = Check::run("bucket", async {
+            self.client
+                .head_bucket()
+                .bucket(bucket.clone())
+                .send()
+                .await
+                .with_context(|| "Failed to head bucket")
+        })
+        .await;
+        checks.push(check);
+
+        let (check, written) = Check::run("write", async {
+            self.client
+                .put_object()
+                .bucket(bucket.clone())
+                .key(key.clone())
+                .body(aws_sdk_s3::primitives::ByteStream::from_static(b"easy-fm"))
+                .send()
+                .await
+                .with_context(|| "Failed to put probe object")
+        })
+        .await;
+        checks.push(check);
+        if written.is_none() {
+            return checks;
+        }
+
+        let (check, _) = Check::run("read", async {
+            self.client
+                .get_object()
+                .bucket(bucket.clone())
+                .key(key.clone())
+                .send()
+                .await
+                .with_context(|| "Failed to get probe object")?
+                .body
+                .collect()
+                .await
+                .with_context(|| "Failed to read probe object")
+        })
+        .await;
+        checks.push(check);
+
+        let (check, _) = Check::run("delete", async {
+            self.client
+                .delete_object()
+                .bucket(bucket.clone())
+                .key(key.clone())
+                .send()
+                .await
+                .with_context(|| "Failed to delete probe object")
+        })
+        .await;
+        checks.push(check);
+        checks
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
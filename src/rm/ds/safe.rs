@@ -1,30 +1,21 @@
-use std::{
-    ops::{Deref, DerefMut},
-    sync::Arc,
-};
-use tokio::sync::Mutex;
+use std::{ops::Deref, sync::Arc};
 
 use super::DataStorage;
 
+/// A storage shared between the records and composite storages using it
 #[derive(Clone)]
-pub struct SafeDs(Arc<Mutex<Box<dyn DataStorage + Send + Sync>>>);
+pub struct SafeDs(Arc<dyn DataStorage + Send + Sync>);
 
 impl SafeDs {
     pub fn new(ds: Box<dyn DataStorage + Send + Sync>) -> Self {
-        Self(Arc::new(Mutex::new(ds)))
+        Self(Arc::from(ds))
     }
 }
 
 impl Deref for SafeDs {
-    type Target = Arc<Mutex<Box<dyn DataStorage + Send + Sync>>>;
+    type Target = dyn DataStorage + Send + Sync;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
-    }
-}
-
-impl DerefMut for SafeDs {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &*self.0
     }
 }
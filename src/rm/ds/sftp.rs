@@ -0,0 +1,416 @@
+use std::{
+    any::Any,
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use sha2::{Digest, Sha256};
+
+use crate::error::Error;
+
+use super::{contained, throttle, Check, DataStorage, Meter, Observer};
+
+/// Bytes read or written per request
+const CHUNK: usize = 1 << 20;
+/// Longest wait for the server to connect or answer a request, so that a
+/// stalled server fails the transfer instead of holding the session
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// `LIBSSH2_FX_NO_SUCH_FILE`
+const NO_SUCH_FILE: i32 = 2;
+
+fn default_port() -> u16 {
+    22
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct SftpConfig {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub user: String,
+    /// Private key file, authenticating instead of the password, which
+    /// then unlocks the key
+    #[serde(default)]
+    pub key: Option<PathBuf>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Directory the objects are stored under
+    pub dir: String,
+    /// SHA-256 fingerprint of the host key, as printed by `ssh-keygen -l`,
+    /// checked instead of `~/.ssh/known_hosts`
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+}
+
+pub struct Sftp {
+    config: SftpConfig,
+    /// The session, opened on first use and again after a failure
+    conn: Arc<Mutex<Option<ssh2::Sftp>>>,
+}
+
+fn connect(config: &SftpConfig) -> Result<ssh2::Sftp> {
+    let tcp = (config.host.as_str(), config.port)
+        .to_socket_addrs()
+        .and_then(|addrs| {
+            let mut last = None;
+            for addr in addrs {
+                match TcpStream::connect_timeout(&addr, TIMEOUT) {
+                    Ok(tcp) => return Ok(tcp),
+                    Err(e) => last = Some(e),
+                }
+            }
+            Err(last.unwrap_or_else(|| std::io::ErrorKind::NotFound.into()))
+        })
+        .with_context(|| format!("Failed to connect to {}:{}", config.host, config.port))?;
+    let mut session = ssh2::Session::new().with_context(|| "Failed to create SSH session")?;
+    // `LIBSSH2_ERROR_TIMEOUT` is retried as transient
+    session.set_timeout(TIMEOUT.as_millis() as u32);
+    session.set_tcp_stream(tcp);
+    session
+        .handshake()
+        .with_context(|| "Failed to handshake with SSH server")?;
+    // the credentials must not be sent to an impostor
+    verify_host(&session, config)?;
+    match &config.key {
+        Some(key) => {
+            session.userauth_pubkey_file(&config.user, None, key, config.password.as_deref())
+        }
+        None => {
+            session.userauth_password(&config.user, config.password.as_deref().unwrap_or_default())
+        }
+    }
+    .with_context(|| format!("Failed to authenticate as {}", config.user))?;
+    session.sftp().with_context(|| "Failed to start SFTP")
+}
+
+/// Check the host key against the configured fingerprint or `~/.ssh/known_hosts`
+fn verify_host(session: &ssh2::Session, config: &SftpConfig) -> Result<()> {
+    let (key, _) = session
+        .host_key()
+        .with_context(|| "SSH server sent no host key")?;
+    if let Some(expected) = &config.fingerprint {
+        let actual = fingerprint(key);
+        if actual != expected.trim_start_matches("SHA256:") {
+            Err(anyhow::anyhow!(
+                "Host key of {} is SHA256:{actual}, not the configured {expected}",
+                config.host
+            ))?;
+        }
+        return Ok(());
+    }
+    let file = home::home_dir()
+        .with_context(|| "Failed to get home directory")?
+        .join(".ssh/known_hosts");
+    let mut known = session
+        .known_hosts()
+        .with_context(|| "Failed to read known hosts")?;
+    known
+        .read_file(&file, ssh2::KnownHostFileKind::OpenSSH)
+        .with_context(|| format!("Failed to read {}", file.display()))?;
+    match known.check_port(&config.host, config.port, key) {
+        ssh2::CheckResult::Match => Ok(()),
+        ssh2::CheckResult::Mismatch => Err(anyhow::anyhow!(
+            "Host key of {} does not match {}",
+            config.host,
+            file.display()
+        )),
+        ssh2::CheckResult::NotFound => Err(anyhow::anyhow!(
+            "Host {} is not in {}, add it or configure its fingerprint (SHA256:{})",
+            config.host,
+            file.display(),
+            fingerprint(key)
+        )),
+        ssh2::CheckResult::Failure => Err(anyhow::anyhow!("Failed to check host key")),
+    }
+}
+
+/// The SHA-256 fingerprint of a host key, without the `SHA256:` prefix
+fn fingerprint(key: &[u8]) -> String {
+    base64::engine::general_purpose::STANDARD_NO_PAD.encode(Sha256::digest(key))
+}
+
+/// Create `dir` and its missing parents
+fn mkdirs(sftp: &ssh2::Sftp, dir: &Path) -> Result<()> {
+    if dir.as_os_str().is_empty() || sftp.stat(dir).is_ok() {
+        return Ok(());
+    }
+    if let Some(parent) = dir.parent() {
+        mkdirs(sftp, parent)?;
+    }
+    sftp.mkdir(dir, 0o755)
+        .with_context(|| format!("Failed to create directory {}", dir.display()))
+}
+
+/// Names of the files below `dir`, relative to `base`
+fn walk(sftp: &ssh2::Sftp, base: &Path, dir: &Path, names: &mut Vec<String>) -> Result<()> {
+    let entries = sftp
+        .readdir(dir)
+        .with_context(|| format!("Failed to list {}", dir.display()))?;
+    for (path, stat) in entries {
+        if stat.is_dir() {
+            walk(sftp, base, &path, names)?;
+        } else if stat.is_file() {
+            if let Ok(rel) = path.strip_prefix(base) {
+                names.push(rel.to_string_lossy().to_string());
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Sftp {
+    pub fn new(config: SftpConfig) -> Self {
+        Self {
+            config,
+            conn: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Path of an object, which must stay below the directory
+    fn remote(&self, name: &str) -> Result<PathBuf> {
+        if !contained(name) {
+            Err(anyhow::anyhow!("Invalid object name {name:?}"))?;
+        }
+        Ok(Path::new(&self.config.dir).join(name))
+    }
+
+    fn link(&self, remote: &Path) -> String {
+        format!(
+            "sftp://{}@{}:{}{}",
+            self.config.user,
+            self.config.host,
+            self.config.port,
+            remote.display()
+        )
+    }
+
+    /// Run `f` with the session on the blocking thread pool
+    async fn with<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&ssh2::Sftp) -> Result<T> + Send + 'static,
+    {
+        let (config, conn) = (self.config.clone(), self.conn.clone());
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().unwrap();
+            let sftp = match conn.take() {
+                Some(sftp) => sftp,
+                None => connect(&config)?,
+            };
+            let res = f(&sftp);
+            // the session may be broken, so a failed one is not reused
+            if res.is_ok() {
+                *conn = Some(sftp);
+            }
+            res
+        })
+        .await
+        .expect("Failed to join blocking task")
+    }
+}
+
+#[async_trait::async_trait]
+impl DataStorage for Sftp {
+    async fn get(&self, name: String, path: Option<&Path>) -> Result<()> {
+        self.get_with_progress(name, path, &|_| {}).await
+    }
+    async fn put(&self, name: String, path: &Path) -> Result<String> {
+        self.put_with_progress(name, path, &|_| {}).await
+    }
+    async fn get_with_progress(
+        &self,
+        name: String,
+        path: Option<&Path>,
+        observer: &Observer,
+    ) -> Result<()> {
+        let mut file = std::fs::File::create(path.unwrap_or(Path::new(&name)))
+            .map_err(|err| Error::FileError(format!("Failed to create local file: {err:?}")))?;
+        let remote = self.remote(&name)?;
+        let stat = remote.clone();
+        let total = self
+            .with(move |sftp| {
+                sftp.stat(&stat)
+                    .with_context(|| format!("Failed to stat {}", stat.display()))
+            })
+            .await?
+            .size;
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<Vec<u8>>(4);
+        let read = self.with(move |sftp| {
+            let mut file = sftp
+                .open(&remote)
+                .with_context(|| format!("Failed to open {}", remote.display()))?;
+            loop {
+                let mut buf = vec![0; CHUNK];
+                let n = file
+                    .read(&mut buf)
+                    .with_context(|| "Failed to read from SFTP")?;
+                if n == 0 {
+                    return Ok(());
+                }
+                buf.truncate(n);
+                // the receiver is gone when the local file failed
+                if tx.blocking_send(buf).is_err() {
+                    return Ok(());
+                }
+            }
+        });
+        let write = async {
+            let mut meter = Meter::new(observer, &name, total);
+            while let Some(buf) = rx.recv().await {
+                file.write_all(&buf)
+                    .with_context(|| "Failed to write from SFTP to local file")?;
+                meter.add(buf.len() as u64);
+                throttle(buf.len() as u64).await;
+            }
+            Ok::<_, anyhow::Error>(())
+        };
+        let (read, write) = tokio::join!(read, write);
+        write?;
+        read
+    }
+    async fn put_with_progress(
+        &self,
+        name: String,
+        path: &Path,
+        observer: &Observer,
+    ) -> Result<String> {
+        let mut file = std::fs::File::open(path).map_err(|e| Error::FileError(e.to_string()))?;
+        let len = file
+            .metadata()
+            .map_err(|e| Error::FileError(e.to_string()))?
+            .len();
+        let remote = self.remote(&name)?;
+        let link = self.link(&remote);
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<Vec<u8>>(4);
+        let write = self.with(move |sftp| {
+            if let Some(parent) = remote.parent() {
+                mkdirs(sftp, parent)?;
+            }
+            let mut file = sftp
+                .create(&remote)
+                .with_context(|| format!("Failed to create {}", remote.display()))?;
+            while let Some(buf) = rx.blocking_recv() {
+                file.write_all(&buf)
+                    .with_context(|| "Failed to write to SFTP")?;
+            }
+            Ok(())
+        });
+        let read = async {
+            let mut meter = Meter::new(observer, &name, Some(len));
+            loop {
+                let mut buf = vec![0; CHUNK];
+                let n = file
+                    .read(&mut buf)
+                    .map_err(|e| Error::FileError(e.to_string()))?;
+                if n == 0 {
+                    return Ok::<_, anyhow::Error>(());
+                }
+                buf.truncate(n);
+                throttle(n as u64).await;
+                // the sender is gone when the remote file failed
+                if tx.send(buf).await.is_err() {
+                    return Ok(());
+                }
+                meter.add(n as u64);
+            }
+        };
+        let (write, read) = tokio::join!(write, read);
+        if let Err(e) = read {
+            // the writer took the dropped sender for the end of the file
+            if let Err(e) = self.del(name.clone()).await {
+                tracing::warn!("Failed to delete partial {name}: {e:#}");
+            }
+            return Err(e);
+        }
+        write?;
+        Ok(link)
+    }
+    async fn del(&self, name: String) -> Result<()> {
+        let remote = self.remote(&name)?;
+        self.with(move |sftp| match sftp.unlink(&remote) {
+            // like S3, deleting a file that is gone succeeds
+            Err(e) if e.code() == ssh2::ErrorCode::SFTP(NO_SUCH_FILE) => Ok(()),
+            res => res.with_context(|| format!("Failed to delete {}", remote.display())),
+        })
+        .await
+    }
+    async fn list(&self, prefix: Option<&str>) -> Result<Vec<String>> {
+        let base = PathBuf::from(&self.config.dir);
+        let mut names = self
+            .with(move |sftp| {
+                let mut names = Vec::new();
+                walk(sftp, &base, &base, &mut names)?;
+                Ok(names)
+            })
+            .await?;
+        if let Some(prefix) = prefix {
+            names.retain(|x| x.starts_with(prefix));
+        }
+        Ok(names)
+    }
+    async fn health_check(&self) -> Vec<Check> {
+        let dir = PathBuf::from(&self.config.dir);
+        let probe =
+            Path::new(&self.config.dir).join(format!(".easy-fm-probe-{}", uuid::Uuid::new_v4()));
+        let mut checks = Vec::new();
+
+        let (check, _) = Check::run(
+            "dir",
+            self.with(move |sftp| {
+                sftp.stat(&dir)
+                    .with_context(|| format!("Failed to stat {}", dir.display()))
+            }),
+        )
+        .await;
+        checks.push(check);
+
+        let path = probe.clone();
+        let (check, written) = Check::run(
+            "write",
+            self.with(move |sftp| {
+                sftp.create(&path)
+                    .with_context(|| "Failed to create probe file")?
+                    .write_all(b"easy-fm")
+                    .with_context(|| "Failed to write probe file")
+            }),
+        )
+        .await;
+        checks.push(check);
+        if written.is_none() {
+            return checks;
+        }
+
+        let path = probe.clone();
+        let (check, _) = Check::run(
+            "read",
+            self.with(move |sftp| {
+                let mut buf = Vec::new();
+                sftp.open(&path)
+                    .with_context(|| "Failed to open probe file")?
+                    .read_to_end(&mut buf)
+                    .with_context(|| "Failed to read probe file")
+            }),
+        )
+        .await;
+        checks.push(check);
+
+        let (check, _) = Check::run(
+            "delete",
+            self.with(move |sftp| {
+                sftp.unlink(&probe)
+                    .with_context(|| "Failed to delete probe file")
+            }),
+        )
+        .await;
+        checks.push(check);
+        checks
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
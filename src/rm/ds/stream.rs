@@ -0,0 +1,86 @@
+use std::{
+    fs::File,
+    io::{Read, Write},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use reqwest::RequestBuilder;
+
+use crate::error::Error;
+
+use super::{throttle, Meter, Observer};
+
+/// Bytes read from a local file per chunk of an upload
+const CHUNK: usize = 1 << 20;
+
+/// Stream the response to `req` to a local file
+pub(super) async fn download(
+    req: RequestBuilder,
+    name: &str,
+    path: Option<&Path>,
+    observer: &Observer,
+) -> Result<()> {
+    let mut file = File::create(path.unwrap_or(Path::new(name)))
+        .map_err(|err| Error::FileError(format!("Failed to create local file: {err:?}")))?;
+    let mut res = req.send().await.and_then(|x| x.error_for_status())?;
+    let mut meter = Meter::new(observer, name, res.content_length());
+    while let Some(bytes) = res
+        .chunk()
+        .await
+        .with_context(|| "Failed to read from download stream")?
+    {
+        file.write_all(&bytes)
+            .with_context(|| "Failed to write from download stream to local file")?;
+        meter.add(bytes.len() as u64);
+        throttle(bytes.len() as u64).await;
+    }
+    Ok(())
+}
+
+/// Stream a local file as the body of `req`
+pub(super) async fn upload(
+    req: RequestBuilder,
+    name: &str,
+    path: &Path,
+    observer: &Observer,
+) -> Result<()> {
+    let mut file = File::open(path).map_err(|e| Error::FileError(e.to_string()))?;
+    let len = file
+        .metadata()
+        .map_err(|e| Error::FileError(e.to_string()))?
+        .len();
+
+    // the body is sent by another task, so it is fed from this one
+    let (tx, rx) = tokio::sync::mpsc::channel::<Vec<u8>>(4);
+    let body = futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|x| (Ok::<_, std::io::Error>(x), rx))
+    });
+    let send = req
+        .header(reqwest::header::CONTENT_LENGTH, len)
+        .body(reqwest::Body::wrap_stream(body))
+        .send();
+    let feed = async move {
+        let mut meter = Meter::new(observer, name, Some(len));
+        loop {
+            let mut buf = vec![0; CHUNK];
+            let n = file
+                .read(&mut buf)
+                .map_err(|e| Error::FileError(e.to_string()))?;
+            if n == 0 {
+                return Ok::<_, anyhow::Error>(());
+            }
+            buf.truncate(n);
+            throttle(n as u64).await;
+            // the receiver is gone when the request failed
+            if tx.send(buf).await.is_err() {
+                return Ok(());
+            }
+            meter.add(n as u64);
+        }
+    };
+    let (res, fed) = tokio::join!(send, feed);
+    fed?;
+    res.and_then(|x| x.error_for_status())?;
+    Ok(())
+}
@@ -0,0 +1,323 @@
+use std::{any::Any, collections::VecDeque, path::Path};
+
+use anyhow::{Context, Result};
+use quick_xml::events::Event;
+use reqwest::{Method, StatusCode, Url};
+
+use super::{
+    contained,
+    s3::encode_key,
+    stream::{download, upload},
+    Check, DataStorage, Observer,
+};
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct WebdavConfig {
+    /// URL of the collection the objects are stored under
+    pub url: String,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+pub struct Webdav {
+    client: reqwest::Client,
+    config: WebdavConfig,
+}
+
+/// Decode the `%XX` escapes of a URL path
+fn decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|x| std::str::from_utf8(x).ok())
+            .and_then(|x| u8::from_str_radix(x, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
+
+/// The hrefs of a `multistatus` response, with whether each is a collection
+fn multistatus(xml: &str) -> Result<Vec<(String, bool)>> {
+    let mut reader = quick_xml::Reader::from_str(xml);
+    let mut entries = Vec::new();
+    let (mut href, mut collection, mut in_href) = (None, false, false);
+    loop {
+        match reader
+            .read_event()
+            .with_context(|| "Failed to parse PROPFIND response")?
+        {
+            Event::Start(e) => match e.local_name().as_ref() {
+                b"response" => (href, collection) = (None, false),
+                b"href" => in_href = true,
+                b"collection" => collection = true,
+                _ => {}
+            },
+            Event::Empty(e) if e.local_name().as_ref() == b"collection" => collection = true,
+            Event::Text(text) if in_href => {
+                href = Some(
+                    text.unescape()
+                        .with_context(|| "Failed to parse PROPFIND response")?
+                        .to_string(),
+                );
+            }
+            Event::End(e) => match e.local_name().as_ref() {
+                b"href" => in_href = false,
+                b"response" => entries.extend(href.take().map(|x| (x, collection))),
+                _ => {}
+            },
+            Event::Eof => return Ok(entries),
+            _ => {}
+        }
+    }
+}
+
+impl Webdav {
+    pub fn new(config: WebdavConfig) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            config,
+        }
+    }
+
+    /// URL of an object, which must stay below the collection
+    fn url(&self, name: &str) -> Result<String> {
+        if !contained(name) {
+            Err(anyhow::anyhow!("Invalid object name {name:?}"))?;
+        }
+        Ok(format!(
+            "{}/{}",
+            self.config.url.trim_end_matches('/'),
+            encode_key(name)
+        ))
+    }
+
+    fn request(&self, method: Method, url: &str) -> reqwest::RequestBuilder {
+        let req = self.client.request(method, url);
+        match &self.config.user {
+            Some(user) => req.basic_auth(user, self.config.password.as_ref()),
+            None => req,
+        }
+    }
+
+    async fn propfind(&self, url: &str, depth: &str) -> Result<Vec<(String, bool)>> {
+        let body = self
+            .request(Method::from_bytes(b"PROPFIND")?, url)
+            .header("Depth", depth)
+            .header("Content-Type", "application/xml")
+            .body(r#"<?xml version="1.0"?><d:propfind xmlns:d="DAV:"><d:prop><d:resourcetype/></d:prop></d:propfind>"#)
+            .send()
+            .await
+            .and_then(|x| x.error_for_status())
+            .with_context(|| format!("Failed to list {url}"))?
+            .text()
+            .await
+            .with_context(|| format!("Failed to read listing of {url}"))?;
+        multistatus(&body)
+    }
+
+    /// Create the collections above `name`, which MKCOL does not
+    async fn mkcols(&self, name: &str) -> Result<()> {
+        let parts = name.split('/').collect::<Vec<_>>();
+        for i in 1..parts.len() {
+            let url = self.url(&parts[..i].join("/"))?;
+            let res = self
+                .request(Method::from_bytes(b"MKCOL")?, &url)
+                .send()
+                .await
+                .with_context(|| format!("Failed to create collection {url}"))?;
+            // an existing collection is not allowed to be created again
+            if !res.status().is_success() && res.status() != StatusCode::METHOD_NOT_ALLOWED {
+                Err(anyhow::anyhow!(
+                    "Failed to create collection {url}: {}",
+                    res.status()
+                ))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl DataStorage for Webdav {
+    async fn get(&self, name: String, path: Option<&Path>) -> Result<()> {
+        self.get_with_progress(name, path, &|_| {}).await
+    }
+    async fn put(&self, name: String, path: &Path) -> Result<String> {
+        self.put_with_progress(name, path, &|_| {}).await
+    }
+    async fn get_with_progress(
+        &self,
+        name: String,
+        path: Option<&Path>,
+        observer: &Observer,
+    ) -> Result<()> {
+        download(
+            self.request(Method::GET, &self.url(&name)?),
+            &name,
+            path,
+            observer,
+        )
+        .await
+        .with_context(|| "Failed to get object from WebDAV")
+    }
+    async fn put_with_progress(
+        &self,
+        name: String,
+        path: &Path,
+        observer: &Observer,
+    ) -> Result<String> {
+        self.mkcols(&name).await?;
+        let url = self.url(&name)?;
+        upload(self.request(Method::PUT, &url), &name, path, observer)
+            .await
+            .with_context(|| "Failed to put object to WebDAV")?;
+        Ok(url)
+    }
+    async fn del(&self, name: String) -> Result<()> {
+        let res = self
+            .request(Method::DELETE, &self.url(&name)?)
+            .send()
+            .await
+            .with_context(|| "Failed to delete object from WebDAV")?;
+        // like S3, deleting an object that is gone succeeds
+        if res.status() != StatusCode::NOT_FOUND {
+            res.error_for_status()
+                .with_context(|| "Failed to delete object from WebDAV")?;
+        }
+        Ok(())
+    }
+    async fn list(&self, prefix: Option<&str>) -> Result<Vec<String>> {
+        let base = Url::parse(&format!("{}/", self.config.url.trim_end_matches('/')))
+            .with_context(|| "Invalid WebDAV URL")?;
+        let root = decode(base.path());
+        let mut names = Vec::new();
+        // servers commonly refuse infinite depth, so collections are walked
+        let mut pending = VecDeque::from([base.to_string()]);
+        while let Some(url) = pending.pop_front() {
+            let here = decode(Url::parse(&url)?.path());
+            for (href, collection) in self.propfind(&url, "1").await? {
+                let href = base.join(&href).with_context(|| "Invalid href")?;
+                let path = decode(href.path());
+                if path.trim_end_matches('/') == here.trim_end_matches('/') {
+                    continue;
+                }
+                if collection {
+                    pending.push_back(href.to_string());
+                } else if let Some(name) = path.strip_prefix(&root) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        if let Some(prefix) = prefix {
+            names.retain(|x| x.starts_with(prefix));
+        }
+        Ok(names)
+    }
+    async fn health_check(&self) -> Vec<Check> {
+        let url = format!(
+            "{}/.easy-fm-probe-{}",
+            self.config.url.trim_end_matches('/'),
+            uuid::Uuid::new_v4()
+        );
+        let mut checks = Vec::new();
+
+        let (check, _) = Check::run("collection", self.propfind(&self.config.url, "0")).await;
+        checks.push(check);
+
+        let (check, written) = Check::run("write", async {
+            self.request(Method::PUT, &url)
+                .body("easy-fm")
+                .send()
+                .await
+                .and_then(|x| x.error_for_status())
+                .with_context(|| "Failed to put probe object")
+        })
+        .await;
+        checks.push(check);
+        if written.is_none() {
+            return checks;
+        }
+
+        let (check, _) = Check::run("read", async {
+            self.request(Method::GET, &url)
+                .send()
+                .await
+                .and_then(|x| x.error_for_status())
+                .with_context(|| "Failed to get probe object")?
+                .bytes()
+                .await
+                .with_context(|| "Failed to read probe object")
+        })
+        .await;
+        checks.push(check);
+
+        let (check, _) = Check::run("delete", async {
+            self.request(Method::DELETE, &url)
+                .send()
+                .await
+                .and_then(|x| x.error_for_status())
+                .with_context(|| "Failed to delete probe object")
+        })
+        .await;
+        checks.push(check);
+        checks
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_escapes() {
+        assert_eq!(decode("/dav/a%20b/c%2Fd.txt"), "/dav/a b/c/d.txt");
+        assert_eq!(decode("/caf%C3%A9"), "/café");
+        // malformed escapes are kept
+        assert_eq!(decode("/100%/%zz%4"), "/100%/%zz%4");
+    }
+
+    #[test]
+    fn multistatus_entries() {
+        let xml = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:multistatus xmlns:d="DAV:">
+  <d:response>
+    <d:href>/dav/</d:href>
+    <d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop></d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/dav/a%20b.txt</d:href>
+    <d:propstat><d:prop><d:resourcetype/></d:prop></d:propstat>
+  </d:response>
+  <D:response xmlns:D="DAV:">
+    <D:href>/dav/sub/</D:href>
+    <D:propstat><D:prop><D:resourcetype><D:collection></D:collection></D:resourcetype></D:prop></D:propstat>
+  </D:response>
+</d:multistatus>"#;
+        assert_eq!(
+            multistatus(xml).unwrap(),
+            vec![
+                ("/dav/".to_string(), true),
+                ("/dav/a%20b.txt".to_string(), false),
+                ("/dav/sub/".to_string(), true),
+            ]
+        );
+        assert!(multistatus("<d:response></d:href>").is_err());
+    }
+}
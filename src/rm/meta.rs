@@ -1,32 +1,78 @@
 use anyhow::Result;
 
-use super::ds::SafeDs;
+use super::{
+    ds::{RetryPolicy, SafeDs},
+    policy::Lifecycle,
+};
 
+#[derive(serde::Serialize)]
 pub struct DataStorageRecord {
     pub id: String,
+    pub name: Option<String>,
     pub r#type: String,
     pub cfg: String,
+    /// Usage in bytes above which puts are warned about
+    pub soft_quota: Option<u64>,
+    /// Usage in bytes above which puts are rejected
+    pub hard_quota: Option<u64>,
+    pub lifecycle: Vec<Lifecycle>,
+    /// How operations are retried, the default policy if none
+    pub retry: Option<RetryPolicy>,
+    /// Bytes per second its transfers are limited to
+    pub limit_rate: Option<u64>,
 }
 
-#[derive(Clone)]
+#[derive(Clone, serde::Serialize)]
 pub struct MetaRecord {
     pub gid: String,
     pub dsid: String,
     pub name: String,
     pub raw: String,
     pub desc: String,
+    /// SHA-256 of the content, unknown for imported files
+    pub hash: Option<String>,
+    /// Size in bytes, unknown for imported files
+    pub size: Option<u64>,
+    pub tags: Vec<String>,
+    /// Unix time the file was put or imported
+    pub created: Option<u64>,
+    /// Unix time after which the file is deleted
+    pub expires: Option<u64>,
+    /// Unix time the local file was last modified when put
+    pub mtime: Option<u64>,
 }
 
 #[async_trait::async_trait]
-pub trait Meta {
-    fn ds_get(&self, dsid: &str) -> Result<SafeDs>;
-    fn ds_put(&self, r#type: &str, config: &str);
-    fn ds_del(&self, dsid: &str);
-    fn ds_ls(&self) -> Vec<DataStorageRecord>;
+pub trait Meta: Send + Sync {
+    /// Resolve a datastore id or name to its id
+    async fn ds_id(&self, dsid: &str) -> Result<String>;
+    async fn ds_get(&self, dsid: &str) -> Result<SafeDs>;
+    async fn ds_put(&self, r#type: &str, name: Option<&str>, config: &str) -> Result<()>;
+    async fn ds_update(&self, dsid: &str, name: Option<&str>, config: Option<&str>) -> Result<()>;
+    async fn ds_del(&self, dsid: &str) -> Result<()>;
+    async fn ds_ls(&self) -> Vec<DataStorageRecord>;
+    /// The record of a single datastore by id
+    async fn ds_record(&self, dsid: &str) -> Result<DataStorageRecord>;
+    async fn ds_quota(&self, dsid: &str, soft: Option<u64>, hard: Option<u64>) -> Result<()>;
+    async fn ds_lifecycle(&self, dsid: &str, rules: &[Lifecycle]) -> Result<()>;
+    async fn ds_retry(&self, dsid: &str, policy: Option<&RetryPolicy>) -> Result<()>;
+    async fn ds_limit_rate(&self, dsid: &str, rate: Option<u64>) -> Result<()>;
+    /// Bytes stored in the datastore
+    async fn ds_usage(&self, dsid: &str) -> u64;
 
-    fn put(&self, meta: MetaRecord);
-    fn del(&self, gid: &str);
-    fn ls(&self, gid: Option<&str>, dsid: Option<&str>, name: Option<&str>) -> Vec<MetaRecord>;
+    async fn put(&self, meta: MetaRecord);
+    /// Delete the replica in `dsid`, or all replicas if it is `None`
+    async fn del(&self, gid: &str, dsid: Option<&str>);
+    /// Replace the replica of `meta.gid` in `dsid`
+    async fn update(&self, dsid: &str, meta: MetaRecord);
+    /// Records referencing the object `raw` in `dsid`
+    async fn refs(&self, dsid: &str, raw: &str) -> Vec<MetaRecord>;
+    async fn ls(
+        &self,
+        gid: Option<&str>,
+        dsid: Option<&str>,
+        name: Option<&str>,
+    ) -> Vec<MetaRecord>;
 }
 
 pub fn build(r#type: &str, config: &str) -> Result<Box<dyn Meta>, serde_json::Error> {
@@ -1,132 +1,524 @@
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
 use anyhow::{Context, Result};
+use rusqlite::OptionalExtension;
 
-use super::{DataStorageRecord, Meta, MetaRecord};
+use super::{DataStorageRecord, Lifecycle, Meta, MetaRecord, RetryPolicy};
 use crate::{
     error::Error,
     rm::{build, ds::SafeDs},
 };
 use std::collections::HashMap;
 
+/// SQLite metadata, queried on the blocking thread pool
 pub struct Local {
-    gid_conn: rusqlite::Connection,
-    datastore_conn: Mutex<HashMap<String, SafeDs>>,
+    gid_conn: Arc<Mutex<rusqlite::Connection>>,
+    datastore_conn: Arc<Mutex<HashMap<String, SafeDs>>>,
 }
 
+/// Columns of the `map` table
+const MAP: &str = "(
+    gid TEXT NOT NULL,
+    dsid INTEGER NOT NULL REFERENCES rm(id),
+    name TEXT NOT NULL,
+    raw TEXT NOT NULL,
+    discription TEXT NOT NULL,
+    hash TEXT,
+    size INTEGER,
+    tags TEXT,
+    created INTEGER,
+    expires INTEGER,
+    mtime INTEGER
+)";
+
 pub fn init(path: &str) {
     let conn = rusqlite::Connection::open_with_flags(
         path,
         rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE | rusqlite::OpenFlags::SQLITE_OPEN_CREATE,
     )
     .expect("Failed to open database");
+    migrate(&conn);
+}
+
+/// Create the tables, or bring those of an older version up to date
+fn migrate(conn: &rusqlite::Connection) {
     conn.execute(
         "CREATE TABLE IF NOT EXISTS rm (
                 id INTEGER PRIMARY KEY,
                 type TEXT NOT NULL,
-                cfg TEXT NOT NULL
+                cfg TEXT NOT NULL,
+                name TEXT UNIQUE,
+                soft_quota INTEGER,
+                hard_quota INTEGER,
+                lifecycle TEXT,
+                retry TEXT,
+                limit_rate INTEGER
             )",
         [],
     )
     .expect("Failed to create table");
+    add_column(
+        conn,
+        "rm",
+        "name",
+        "ALTER TABLE rm ADD COLUMN name TEXT;
+        CREATE UNIQUE INDEX rm_name ON rm (name);",
+    );
+    add_column(
+        conn,
+        "rm",
+        "soft_quota",
+        "ALTER TABLE rm ADD COLUMN soft_quota INTEGER;
+        ALTER TABLE rm ADD COLUMN hard_quota INTEGER;",
+    );
+    add_column(
+        conn,
+        "rm",
+        "lifecycle",
+        "ALTER TABLE rm ADD COLUMN lifecycle TEXT",
+    );
+    add_column(conn, "rm", "retry", "ALTER TABLE rm ADD COLUMN retry TEXT");
+    add_column(
+        conn,
+        "rm",
+        "limit_rate",
+        "ALTER TABLE rm ADD COLUMN limit_rate INTEGER",
+    );
+    conn.execute(&format!("CREATE TABLE IF NOT EXISTS map {MAP}"), [])
+        .expect("Failed to create table");
+    add_column(conn, "map", "hash", "ALTER TABLE map ADD COLUMN hash TEXT");
+    add_column(
+        conn,
+        "map",
+        "size",
+        "ALTER TABLE map ADD COLUMN size INTEGER;
+        ALTER TABLE map ADD COLUMN tags TEXT;",
+    );
+    add_column(
+        conn,
+        "map",
+        "created",
+        "ALTER TABLE map ADD COLUMN created INTEGER;
+        ALTER TABLE map ADD COLUMN expires INTEGER;",
+    );
+    add_column(
+        conn,
+        "map",
+        "mtime",
+        "ALTER TABLE map ADD COLUMN mtime INTEGER",
+    );
+    add_foreign_key(conn);
     conn.execute(
-        "CREATE TABLE IF NOT EXISTS map (
-                gid TEXT NOT NULL,
-                dsid INTEGER NOT NULL,
-                name TEXT NOT NULL,
-                raw TEXT NOT NULL,
-                discription TEXT NOT NULL
-            )",
+        "CREATE INDEX IF NOT EXISTS map_object ON map (dsid, raw)",
         [],
     )
-    .expect("Failed to create table");
+    .expect("Failed to create index");
+    conn.execute(
+        "CREATE UNIQUE INDEX IF NOT EXISTS map_replica ON map (gid, dsid)",
+        [],
+    )
+    .expect("Failed to create index");
+}
+
+/// Rebuild `map` of databases made before it referenced `rm`, which SQLite
+/// cannot add to an existing table, dropping the records of deleted datastores
+fn add_foreign_key(conn: &rusqlite::Connection) {
+    let exists: bool = conn
+        .query_row(
+            "SELECT COUNT(*) FROM pragma_foreign_key_list('map')",
+            [],
+            |row| row.get(0),
+        )
+        .expect("Failed to query foreign keys");
+    if exists {
+        return;
+    }
+    let dangling: u64 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM map WHERE dsid NOT IN (SELECT id FROM rm)",
+            [],
+            |row| row.get(0),
+        )
+        .expect("Failed to count records");
+    if dangling > 0 {
+        tracing::warn!("Dropping {dangling} records of deleted datastores");
+    }
+    conn.execute_batch(&format!(
+        "BEGIN;
+        CREATE TABLE map_new {MAP};
+        INSERT INTO map_new
+            SELECT gid, dsid, name, raw, discription, hash, size, tags, created, expires, mtime
+            FROM map WHERE dsid IN (SELECT id FROM rm);
+        DROP TABLE map;
+        ALTER TABLE map_new RENAME TO map;
+        COMMIT;"
+    ))
+    .expect("Failed to add foreign key");
+}
+
+/// Add columns missing from databases made by older versions
+fn add_column(conn: &rusqlite::Connection, table: &str, column: &str, sql: &str) {
+    let exists: bool = conn
+        .query_row(
+            "SELECT COUNT(*) FROM pragma_table_info(?) WHERE name = ?",
+            [table, column],
+            |row| row.get(0),
+        )
+        .expect("Failed to query table info");
+    if !exists {
+        conn.execute_batch(sql).expect("Failed to alter table");
+    }
+}
+
+fn record(row: &rusqlite::Row) -> rusqlite::Result<MetaRecord> {
+    let tags: Option<String> = row.get(7)?;
+    Ok(MetaRecord {
+        gid: row.get(0)?,
+        dsid: row.get::<usize, i32>(1)?.to_string(),
+        name: row.get(2)?,
+        raw: row.get(3)?,
+        desc: row.get(4)?,
+        hash: row.get(5)?,
+        size: row.get(6)?,
+        tags: tags
+            .map(|x| serde_json::from_str(&x).unwrap_or_default())
+            .unwrap_or_default(),
+        created: row.get(8)?,
+        expires: row.get(9)?,
+        mtime: row.get(10)?,
+    })
+}
+
+fn ds_record(row: &rusqlite::Row) -> rusqlite::Result<DataStorageRecord> {
+    Ok(DataStorageRecord {
+        id: row.get::<usize, i32>(0)?.to_string(),
+        r#type: row.get(1)?,
+        cfg: row.get(2)?,
+        name: row.get(3)?,
+        soft_quota: row.get(4)?,
+        hard_quota: row.get(5)?,
+        lifecycle: row
+            .get::<usize, Option<String>>(6)?
+            .map(|x| serde_json::from_str(&x).unwrap_or_default())
+            .unwrap_or_default(),
+        retry: row
+            .get::<usize, Option<String>>(7)?
+            .and_then(|x| serde_json::from_str(&x).ok()),
+        limit_rate: row.get(8)?,
+    })
 }
+
 impl Local {
     pub fn new(path: &str) -> Self {
         let conn = rusqlite::Connection::open(path).expect("Failed to open database");
+        // databases of older versions are migrated on open, not only by `init`
+        migrate(&conn);
+        conn.pragma_update(None, "foreign_keys", true)
+            .expect("Failed to enable foreign keys");
         Self {
-            gid_conn: conn,
-            datastore_conn: std::sync::Mutex::new(HashMap::new()),
+            gid_conn: Arc::new(Mutex::new(conn)),
+            datastore_conn: Arc::new(Mutex::new(HashMap::new())),
         }
     }
+
+    /// Run `f` with the connection on the blocking thread pool
+    async fn with<T, F>(&self, f: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce(&rusqlite::Connection) -> T + Send + 'static,
+    {
+        let conn = self.gid_conn.clone();
+        tokio::task::spawn_blocking(move || f(&conn.lock().unwrap()))
+            .await
+            .expect("Failed to join blocking task")
+    }
+}
+
+fn ds_id(conn: &rusqlite::Connection, dsid: &str) -> Result<String> {
+    conn.query_row(
+        "SELECT id FROM rm WHERE id = ?1 OR name = ?1",
+        [dsid],
+        |row| row.get::<usize, i32>(0),
+    )
+    .optional()
+    .with_context(|| "Failed to query")?
+    .map(|id| id.to_string())
+    .ok_or(Error::NotFound(format!("Datastore {dsid} not found")).into())
+}
+
+/// Build a datastore, `within` the composite ones being built of it
+fn ds_get(
+    conn: &rusqlite::Connection,
+    cache: &Mutex<HashMap<String, SafeDs>>,
+    dsid: &str,
+    within: &[&str],
+) -> Result<SafeDs> {
+    if let Some(cli) = cache.lock().unwrap().get(dsid) {
+        return Ok(cli.clone());
+    }
+    if within.contains(&dsid) {
+        Err(anyhow::anyhow!(
+            "Datastore {dsid} is made of itself through {}",
+            within.join(", ")
+        ))?;
+    }
+    let mut stmt = conn
+        .prepare("SELECT * FROM rm WHERE id = ?")
+        .expect("Failed to prepare statement");
+    let (r#type, cfg) = stmt
+        .query_map([dsid], |row| {
+            let r#type: String = row.get(1)?;
+            let cfg: String = row.get(2)?;
+            Ok((r#type, cfg))
+        })
+        .expect("Failed to query map")
+        .next()
+        .ok_or(Error::NotFound("Datastore not found".to_string()))?
+        .with_context(|| "Failed to get row")?;
+    let within = [within, &[dsid]].concat();
+    let cli = build(&r#type, &cfg, &|x| {
+        ds_get(conn, cache, &ds_id(conn, x)?, &within)
+    })
+    .with_context(|| "Failed to build")?;
+    Ok(cache
+        .lock()
+        .unwrap()
+        .entry(dsid.to_string())
+        .or_insert(SafeDs::new(cli))
+        .clone())
 }
 
 #[async_trait::async_trait]
 impl Meta for Local {
-    fn ds_get(&self, dsid: &str) -> Result<SafeDs> {
+    async fn ds_id(&self, dsid: &str) -> Result<String> {
+        let dsid = dsid.to_string();
+        self.with(move |conn| ds_id(conn, &dsid)).await
+    }
+    async fn ds_get(&self, dsid: &str) -> Result<SafeDs> {
         if let Some(cli) = self.datastore_conn.lock().unwrap().get(dsid) {
             return Ok(cli.clone());
         }
-        let mut stmt = self
-            .gid_conn
-            .prepare("SELECT * FROM rm WHERE id = ?")
-            .expect("Failed to prepare statement");
-        let (r#type, cfg) = stmt
-            .query_map([dsid], |row| {
-                let r#type: String = row.get(1)?;
-                let cfg: String = row.get(2)?;
-                Ok((r#type, cfg))
-            })
-            .expect("Failed to query map")
-            .next()
-            .ok_or(Error::NotFound("Datastore not found".to_string()))?
-            .with_context(|| "Failed to get row")?;
-        let cli = build(&r#type, &cfg).expect("Failed to build");
-        Ok(self
-            .datastore_conn
-            .lock()
-            .unwrap()
-            .entry(dsid.to_string())
-            .or_insert(SafeDs::new(cli))
-            .clone())
-    }
-    fn ds_put(&self, r#type: &str, cfg: &str) {
-        self.gid_conn
-            .execute("INSERT INTO rm (type, cfg) VALUES (?, ?)", [r#type, cfg])
-            .expect("Failed to insert");
+        let dsid = dsid.to_string();
+        let cache = self.datastore_conn.clone();
+        self.with(move |conn| ds_get(conn, &cache, &dsid, &[]))
+            .await
     }
-    fn ds_del(&self, dsid: &str) {
-        self.gid_conn
-            .execute("DELETE FROM rm WHERE id = ?", [dsid])
-            .expect("Failed to delete");
+    async fn ds_put(&self, r#type: &str, name: Option<&str>, cfg: &str) -> Result<()> {
+        let (r#type, name, cfg) = (
+            r#type.to_string(),
+            name.map(|x| x.to_string()),
+            cfg.to_string(),
+        );
+        self.with(move |conn| {
+            conn.execute(
+                "INSERT INTO rm (type, cfg, name) VALUES (?, ?, ?)",
+                rusqlite::params![r#type, cfg, name],
+            )
+            .with_context(|| "Failed to insert")
+        })
+        .await?;
+        Ok(())
     }
-    fn ds_ls(&self) -> Vec<DataStorageRecord> {
-        let mut stmt = self
-            .gid_conn
-            .prepare("SELECT * FROM rm")
-            .expect("Failed to prepare statement");
-        stmt.query_map([], |row| {
-            Ok(DataStorageRecord {
-                id: row.get::<usize, i32>(0)?.to_string(),
-                r#type: row.get(1)?,
-                cfg: row.get(2)?,
-            })
+    async fn ds_update(&self, dsid: &str, name: Option<&str>, cfg: Option<&str>) -> Result<()> {
+        let (dsid, name, cfg) = (
+            dsid.to_string(),
+            name.map(|x| x.to_string()),
+            cfg.map(|x| x.to_string()),
+        );
+        let cfg_changed = cfg.is_some();
+        self.with(move |conn| {
+            if let Some(name) = name {
+                conn.execute("UPDATE rm SET name = ? WHERE id = ?", [name, dsid.clone()])
+                    .with_context(|| "Failed to update")?;
+            }
+            if let Some(cfg) = cfg {
+                conn.execute("UPDATE rm SET cfg = ? WHERE id = ?", [cfg, dsid])
+                    .with_context(|| "Failed to update")?;
+            }
+            Ok::<_, anyhow::Error>(())
         })
-        .expect("Failed to query map")
-        .map(|row| row.expect("Failed to get row"))
-        .collect()
-    }
-    fn put(&self, meta: MetaRecord) {
-        self.gid_conn
-            .execute(
-                "INSERT INTO map (gid, dsid, name, raw, discription) VALUES (?, ?, ?, ?, ?)",
-                [
+        .await?;
+        if cfg_changed {
+            // composite datastores hold the ones they are made of
+            self.datastore_conn.lock().unwrap().clear();
+        }
+        Ok(())
+    }
+    async fn ds_del(&self, dsid: &str) -> Result<()> {
+        let id = dsid.to_string();
+        self.with(move |conn| {
+            conn.execute("DELETE FROM rm WHERE id = ?", [id])
+                .with_context(|| "Failed to delete")
+        })
+        .await?;
+        self.datastore_conn.lock().unwrap().remove(dsid);
+        Ok(())
+    }
+    async fn ds_ls(&self) -> Vec<DataStorageRecord> {
+        self.with(|conn| {
+            let mut stmt = conn
+                .prepare("SELECT * FROM rm")
+                .expect("Failed to prepare statement");
+            stmt.query_map([], ds_record)
+                .expect("Failed to query map")
+                .map(|row| row.expect("Failed to get row"))
+                .collect()
+        })
+        .await
+    }
+    async fn ds_record(&self, dsid: &str) -> Result<DataStorageRecord> {
+        let dsid = dsid.to_string();
+        self.with(move |conn| {
+            conn.query_row("SELECT * FROM rm WHERE id = ?", [&dsid], ds_record)
+                .optional()
+                .with_context(|| "Failed to query")?
+                .ok_or(Error::NotFound(format!("Datastore {dsid} not found")).into())
+        })
+        .await
+    }
+    async fn put(&self, meta: MetaRecord) {
+        self.with(move |conn| {
+            conn.execute(
+                "INSERT INTO map (gid, dsid, name, raw, discription, hash, size, tags, created, expires, mtime) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                rusqlite::params![
                     meta.gid,
                     meta.dsid.to_string(),
                     meta.name,
                     meta.raw,
                     meta.desc,
+                    meta.hash,
+                    meta.size,
+                    serde_json::to_string(&meta.tags).expect("Failed to serialize"),
+                    meta.created,
+                    meta.expires,
+                    meta.mtime,
                 ],
             )
             .expect("Failed to insert");
+        })
+        .await
     }
-    fn del(&self, gid: &str) {
-        self.gid_conn
-            .execute("DELETE FROM map WHERE gid = ?", [gid])
+    async fn del(&self, gid: &str, dsid: Option<&str>) {
+        let (gid, dsid) = (gid.to_string(), dsid.map(|x| x.to_string()));
+        self.with(move |conn| {
+            match dsid {
+                Some(dsid) => {
+                    conn.execute("DELETE FROM map WHERE gid = ? AND dsid = ?", [gid, dsid])
+                }
+                None => conn.execute("DELETE FROM map WHERE gid = ?", [gid]),
+            }
             .expect("Failed to delete");
+        })
+        .await
+    }
+    async fn update(&self, dsid: &str, meta: MetaRecord) {
+        let dsid = dsid.to_string();
+        self.with(move |conn| {
+            conn.execute(
+                "UPDATE map SET dsid = ?, name = ?, raw = ?, discription = ?, hash = ?, size = ?, tags = ?, created = ?, expires = ?, mtime = ? WHERE gid = ? AND dsid = ?",
+                rusqlite::params![
+                    meta.dsid,
+                    meta.name,
+                    meta.raw,
+                    meta.desc,
+                    meta.hash,
+                    meta.size,
+                    serde_json::to_string(&meta.tags).expect("Failed to serialize"),
+                    meta.created,
+                    meta.expires,
+                    meta.mtime,
+                    meta.gid,
+                    dsid
+                ],
+            )
+            .expect("Failed to update");
+        })
+        .await
+    }
+    async fn ds_quota(&self, dsid: &str, soft: Option<u64>, hard: Option<u64>) -> Result<()> {
+        let dsid = dsid.to_string();
+        self.with(move |conn| {
+            conn.execute(
+                "UPDATE rm SET soft_quota = ?, hard_quota = ? WHERE id = ?",
+                rusqlite::params![soft, hard, dsid],
+            )
+            .with_context(|| "Failed to update")
+        })
+        .await?;
+        Ok(())
+    }
+    async fn ds_lifecycle(&self, dsid: &str, rules: &[Lifecycle]) -> Result<()> {
+        let dsid = dsid.to_string();
+        let rules = serde_json::to_string(rules).expect("Failed to serialize");
+        self.with(move |conn| {
+            conn.execute(
+                "UPDATE rm SET lifecycle = ? WHERE id = ?",
+                rusqlite::params![rules, dsid],
+            )
+            .with_context(|| "Failed to update")
+        })
+        .await?;
+        Ok(())
+    }
+    async fn ds_retry(&self, dsid: &str, policy: Option<&RetryPolicy>) -> Result<()> {
+        let dsid = dsid.to_string();
+        let policy = policy.map(|x| serde_json::to_string(x).expect("Failed to serialize"));
+        self.with(move |conn| {
+            conn.execute(
+                "UPDATE rm SET retry = ? WHERE id = ?",
+                rusqlite::params![policy, dsid],
+            )
+            .with_context(|| "Failed to update")
+        })
+        .await?;
+        Ok(())
+    }
+    async fn ds_limit_rate(&self, dsid: &str, rate: Option<u64>) -> Result<()> {
+        let dsid = dsid.to_string();
+        self.with(move |conn| {
+            conn.execute(
+                "UPDATE rm SET limit_rate = ? WHERE id = ?",
+                rusqlite::params![rate, dsid],
+            )
+            .with_context(|| "Failed to update")
+        })
+        .await?;
+        Ok(())
+    }
+    async fn ds_usage(&self, dsid: &str) -> u64 {
+        let dsid = dsid.to_string();
+        // objects shared by several records are only stored once
+        self.with(move |conn| {
+            conn.query_row(
+                "SELECT COALESCE(SUM(size), 0) FROM
+                    (SELECT MAX(size) AS size FROM map WHERE dsid = ? GROUP BY raw)",
+                [dsid],
+                |row| row.get(0),
+            )
+            .expect("Failed to query")
+        })
+        .await
+    }
+    async fn refs(&self, dsid: &str, raw: &str) -> Vec<MetaRecord> {
+        let (dsid, raw) = (dsid.to_string(), raw.to_string());
+        self.with(move |conn| {
+            let mut stmt = conn
+                .prepare("SELECT * FROM map WHERE dsid = ? AND raw = ?")
+                .expect("Failed to prepare statement");
+            stmt.query_map([dsid, raw], record)
+                .expect("Failed to query map")
+                .map(|row| row.expect("Failed to get row"))
+                .collect()
+        })
+        .await
     }
 
-    fn ls(&self, gid: Option<&str>, dsid: Option<&str>, name: Option<&str>) -> Vec<MetaRecord> {
+    async fn ls(
+        &self,
+        gid: Option<&str>,
+        dsid: Option<&str>,
+        name: Option<&str>,
+    ) -> Vec<MetaRecord> {
         let mut q = "SELECT * FROM map".to_string();
         if gid.is_some() || dsid.is_some() || name.is_some() {
             q = q
@@ -138,24 +530,16 @@ impl Meta for Local {
                     .join(" AND ");
         }
         q.push(';');
-        let mut stmt = self
-            .gid_conn
-            .prepare(&q)
-            .expect("Failed to prepare statement");
-        let records = stmt
-            .query_map([], |row| {
-                Ok(MetaRecord {
-                    gid: row.get(0)?,
-                    dsid: row.get::<usize, i32>(1)?.to_string(),
-                    name: row.get(2)?,
-                    raw: row.get(3)?,
-                    desc: row.get(4)?,
-                })
-            })
-            .expect("Failed to query map")
-            .map(|row| row.expect("Failed to get row"))
-            .collect();
-
-        records
+        self.with(move |conn| {
+            let mut stmt = conn.prepare(&q).expect("Failed to prepare statement");
+            let records = stmt
+                .query_map([], record)
+                .expect("Failed to query map")
+                .map(|row| row.expect("Failed to get row"))
+                .collect();
+
+            records
+        })
+        .await
     }
 }
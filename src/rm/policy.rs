@@ -0,0 +1,196 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// What a policy knows about the file being put
+pub struct FileInfo<'a> {
+    pub path: &'a Path,
+    pub size: u64,
+    pub tags: &'a [String],
+    /// Bytes each datastore, by id and by name, may still take under its hard
+    /// quota, `None` for one without
+    pub free: &'a HashMap<String, Option<u64>>,
+}
+
+/// Chooses the datastore of a file put without an explicit one
+pub trait Policy: Send + Sync {
+    /// Return the datastore id or name, or `None` if the policy has no opinion
+    fn select(&self, file: &FileInfo) -> Option<String>;
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "by", rename_all = "snake_case")]
+pub enum Rule {
+    /// Files with one of the extensions, compared case-insensitively
+    Extension { extensions: Vec<String>, ds: String },
+    /// Files of more than `larger_than` bytes
+    Size { larger_than: u64, ds: String },
+    /// Files put with the tag
+    Tag { tag: String, ds: String },
+    /// Any file, rotating over the datastores
+    ///
+    /// The rotation is not persisted: it starts over with the first datastore
+    /// in each process, so it spreads the files of one watch or
+    /// [`RM::put_batch`](crate::rm::RM::put_batch), while separate puts of
+    /// the CLI all go to the first datastore.
+    RoundRobin { ds: Vec<String> },
+    /// Any file, to the datastore with the most room left under its hard
+    /// quota, a datastore without one having unlimited room
+    MostFree { ds: Vec<String> },
+}
+
+/// First matching rule wins, then the default datastore
+#[derive(Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+    default: Option<String>,
+    next: AtomicUsize,
+}
+
+impl Rules {
+    pub fn new(rules: Vec<Rule>, default: Option<String>) -> Self {
+        Self {
+            rules,
+            default,
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Policy for Rules {
+    fn select(&self, file: &FileInfo) -> Option<String> {
+        let ext = file
+            .path
+            .extension()
+            .and_then(|x| x.to_str())
+            .map(|x| x.to_lowercase());
+        self.rules
+            .iter()
+            .find_map(|rule| match rule {
+                Rule::Extension { extensions, ds } => ext
+                    .as_ref()
+                    .filter(|ext| extensions.iter().any(|x| x.to_lowercase() == **ext))
+                    .map(|_| ds.clone()),
+                Rule::Size { larger_than, ds } => (file.size > *larger_than).then(|| ds.clone()),
+                Rule::Tag { tag, ds } => file.tags.contains(tag).then(|| ds.clone()),
+                Rule::RoundRobin { ds } if !ds.is_empty() => {
+                    let i = self.next.fetch_add(1, Ordering::Relaxed);
+                    Some(ds[i % ds.len()].clone())
+                }
+                Rule::RoundRobin { .. } => None,
+                Rule::MostFree { ds } => most_free(ds, file),
+            })
+            .or_else(|| self.default.clone())
+    }
+}
+
+/// The first of the datastores with the most room, if any has room for the file
+fn most_free(ds: &[String], file: &FileInfo) -> Option<String> {
+    let mut best: Option<(&String, u64)> = None;
+    for ds in ds {
+        let Some(free) = file.free.get(ds) else {
+            continue;
+        };
+        let free = free.unwrap_or(u64::MAX);
+        if free >= file.size && best.is_none_or(|(_, x)| free > x) {
+            best = Some((ds, free));
+        }
+    }
+    best.map(|(ds, _)| ds.clone())
+}
+
+/// How many copies of each file to keep, and where
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Replication {
+    /// Copies of each file, including the first one
+    pub copies: usize,
+    /// Datastores for the other copies, in order of preference
+    pub ds: Vec<String>,
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Self {
+            copies: 1,
+            ds: Vec::new(),
+        }
+    }
+}
+
+/// What happens to the files of a datastore as they age
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Lifecycle {
+    /// Delete files put at least `after_days` ago
+    Delete { after_days: u64 },
+    /// Move files put at least `after_days` ago to another datastore
+    Move { after_days: u64, ds: String },
+}
+
+impl Lifecycle {
+    pub fn after_days(&self) -> u64 {
+        match self {
+            Lifecycle::Delete { after_days } | Lifecycle::Move { after_days, .. } => *after_days,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn select(rules: &Rules, size: u64) -> Option<String> {
+        rules.select(&FileInfo {
+            path: Path::new("a.bin"),
+            size,
+            tags: &[],
+            free: &HashMap::new(),
+        })
+    }
+
+    #[test]
+    fn sizes_and_rotation() {
+        let size = Rule::Size {
+            larger_than: 100,
+            ds: "big".to_string(),
+        };
+        let rules = Rules::new(vec![size], Some("main".to_string()));
+        assert_eq!(select(&rules, 100).as_deref(), Some("main"));
+        assert_eq!(select(&rules, 101).as_deref(), Some("big"));
+
+        let ds = vec!["a".to_string(), "b".to_string()];
+        let rules = Rules::new(vec![Rule::RoundRobin { ds }], None);
+        let picked = (0..3)
+            .map(|_| select(&rules, 0).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(picked, ["a", "b", "a"]);
+    }
+
+    #[test]
+    fn most_free_space() {
+        let ds = ["a", "b", "c"].map(|x| x.to_string()).to_vec();
+        let rules = Rules::new(vec![Rule::MostFree { ds }], Some("main".to_string()));
+        let select = |size, free: &[(&str, Option<u64>)]| {
+            let free = free
+                .iter()
+                .map(|(ds, free)| (ds.to_string(), *free))
+                .collect::<HashMap<_, _>>();
+            rules.select(&FileInfo {
+                path: Path::new("a.bin"),
+                size,
+                tags: &[],
+                free: &free,
+            })
+        };
+        let free = [("a", Some(10)), ("b", Some(30)), ("c", Some(20))];
+        assert_eq!(select(5, &free).as_deref(), Some("b"));
+        // no datastore has room for the file
+        assert_eq!(select(50, &free).as_deref(), Some("main"));
+        let free = [("a", Some(10)), ("b", None), ("c", None)];
+        assert_eq!(select(50, &free).as_deref(), Some("b"));
+        // unknown datastores are skipped
+        assert_eq!(select(5, &[]).as_deref(), Some("main"));
+    }
+}
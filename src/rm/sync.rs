@@ -0,0 +1,202 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+
+use super::{ds::contained, hash, mtime, now, MetaRecord, RM};
+use crate::error::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncMode {
+    /// Make the datastore match the local directory
+    Push,
+    /// Make the local directory match the datastore
+    Pull,
+    /// Copy changes both ways, the newer side winning
+    Both,
+}
+
+/// A step of a sync, naming the file relative to the directory and prefix
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(tag = "action", content = "key", rename_all = "snake_case")]
+pub enum SyncAction {
+    Upload(String),
+    Download(String),
+    DeleteRemote(String),
+    DeleteLocal(String),
+}
+
+struct Local {
+    path: PathBuf,
+    size: u64,
+    mtime: Option<u64>,
+}
+
+/// Regular files below `dir`, keyed by their `/`-separated relative path
+fn walk(dir: &Path, rel: &str, files: &mut BTreeMap<String, Local>) -> Result<()> {
+    let entries = std::fs::read_dir(dir).map_err(|e| Error::FileError(e.to_string()))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| Error::FileError(e.to_string()))?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let key = match rel {
+            "" => name,
+            _ => format!("{rel}/{name}"),
+        };
+        let metadata = entry
+            .metadata()
+            .map_err(|e| Error::FileError(e.to_string()))?;
+        if metadata.is_dir() {
+            walk(&entry.path(), &key, files)?;
+        } else if metadata.is_file() {
+            files.insert(
+                key,
+                Local {
+                    path: entry.path(),
+                    size: metadata.len(),
+                    mtime: mtime(&metadata),
+                },
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Whether the local file holds the content of the record
+fn unchanged(local: &Local, mr: &MetaRecord) -> Result<bool> {
+    if mr.size != Some(local.size) {
+        return Ok(false);
+    }
+    // hashing is only needed when the modification times disagree
+    if mr.mtime.is_some() && mr.mtime == local.mtime {
+        return Ok(true);
+    }
+    Ok(mr.hash.is_some() && mr.hash == Some(hash(&local.path)?))
+}
+
+impl RM {
+    /// Mirror a local directory with the files of a datastore under `prefix`
+    ///
+    /// Only files whose size and modification time or hash differ are
+    /// transferred. With `delete`, files missing from the source side are
+    /// deleted from the other one; it has no effect in [`SyncMode::Both`],
+    /// where files missing on either side are copied. With `dry_run`, the
+    /// actions are only returned.
+    pub async fn sync(
+        &self,
+        dir: &Path,
+        dsid: &str,
+        prefix: &str,
+        mode: SyncMode,
+        delete: bool,
+        dry_run: bool,
+    ) -> Result<Vec<SyncAction>> {
+        let dsid = self.meta.ds_id(dsid).await?;
+        let mut local = BTreeMap::new();
+        walk(dir, "", &mut local)?;
+        let mut remote = BTreeMap::new();
+        for mr in self.meta.ls(None, Some(&dsid), None).await {
+            if let Some(key) = mr.raw.strip_prefix(prefix) {
+                // a key like `../x` would be downloaded outside the directory
+                if !contained(key) {
+                    tracing::warn!("Skipping {:?}, which escapes {}", mr.raw, dir.display());
+                    continue;
+                }
+                remote.entry(key.to_string()).or_insert(mr);
+            }
+        }
+
+        let mut actions = Vec::new();
+        let keys = local.keys().chain(remote.keys()).collect::<BTreeSet<_>>();
+        for key in keys {
+            let action = match (local.get(key), remote.get(key)) {
+                (Some(file), Some(mr)) => match unchanged(file, mr)? {
+                    true => None,
+                    false => match mode {
+                        SyncMode::Push => Some(SyncAction::Upload(key.clone())),
+                        SyncMode::Pull => Some(SyncAction::Download(key.clone())),
+                        SyncMode::Both => match file.mtime >= mr.mtime.or(mr.created) {
+                            true => Some(SyncAction::Upload(key.clone())),
+                            false => Some(SyncAction::Download(key.clone())),
+                        },
+                    },
+                },
+                (Some(_), None) => match mode {
+                    SyncMode::Push | SyncMode::Both => Some(SyncAction::Upload(key.clone())),
+                    SyncMode::Pull => delete.then(|| SyncAction::DeleteLocal(key.clone())),
+                },
+                (None, Some(_)) => match mode {
+                    SyncMode::Pull | SyncMode::Both => Some(SyncAction::Download(key.clone())),
+                    SyncMode::Push => delete.then(|| SyncAction::DeleteRemote(key.clone())),
+                },
+                (None, None) => None,
+            };
+            actions.extend(action);
+        }
+        if dry_run {
+            return Ok(actions);
+        }
+
+        for action in &actions {
+            match action {
+                SyncAction::Upload(key) => {
+                    let file = &local[key];
+                    let previous = remote.get(key);
+                    let metadata = std::fs::metadata(&file.path)
+                        .map_err(|e| Error::FileError(e.to_string()))?;
+                    let mr = MetaRecord {
+                        gid: uuid::Uuid::new_v4().to_string(),
+                        dsid: dsid.clone(),
+                        name: key.rsplit('/').next().unwrap_or(key).to_string(),
+                        raw: prefix.to_string() + key,
+                        desc: String::new(),
+                        hash: Some(hash(&file.path)?),
+                        size: Some(metadata.len()),
+                        tags: previous.map(|x| x.tags.clone()).unwrap_or_default(),
+                        created: Some(now()),
+                        expires: None,
+                        mtime: mtime(&metadata),
+                    };
+                    self.put_record(mr, &file.path)
+                        .await
+                        .with_context(|| format!("Failed to upload {key}"))?;
+                    // the object was overwritten in place, only the record is stale
+                    if let Some(previous) = previous {
+                        self.meta.del(&previous.gid, None).await;
+                    }
+                }
+                SyncAction::Download(key) => {
+                    let mr = &remote[key];
+                    let path = dir.join(key);
+                    if let Some(parent) = path.parent() {
+                        std::fs::create_dir_all(parent)
+                            .map_err(|e| Error::FileError(e.to_string()))?;
+                    }
+                    self.get(Some(&mr.gid), None, None, Some(&path))
+                        .await
+                        .with_context(|| format!("Failed to download {key}"))?;
+                    // a matching modification time saves hashing on the next sync
+                    if let Some(mtime) = mr.mtime {
+                        std::fs::File::options()
+                            .write(true)
+                            .open(&path)
+                            .and_then(|f| f.set_modified(UNIX_EPOCH + Duration::from_secs(mtime)))
+                            .map_err(|e| Error::FileError(e.to_string()))?;
+                    }
+                }
+                SyncAction::DeleteRemote(key) => {
+                    self.del(&remote[key].gid)
+                        .await
+                        .with_context(|| format!("Failed to delete {key}"))?;
+                }
+                SyncAction::DeleteLocal(key) => {
+                    std::fs::remove_file(&local[key].path)
+                        .map_err(|e| Error::FileError(e.to_string()))?;
+                }
+            }
+        }
+        Ok(actions)
+    }
+}
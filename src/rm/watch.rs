@@ -0,0 +1,149 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use notify::{EventKind, RecursiveMode, Watcher};
+
+use super::{MetaRecord, RM};
+use crate::error::Error;
+
+/// What to do with a local file once it is uploaded
+#[derive(Debug, Clone, PartialEq)]
+pub enum AfterUpload {
+    Keep,
+    Delete,
+    /// Move it into the directory, which must not be the watched one
+    Move(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+pub struct WatchConfig {
+    /// How long a file must go without writes before it is uploaded
+    pub settle: Duration,
+    /// Attempts after a failed upload, with the delay doubling from a second
+    pub retries: u32,
+    pub after: AfterUpload,
+    pub tags: Vec<String>,
+}
+
+impl Default for WatchConfig {
+    fn default() -> Self {
+        Self {
+            settle: Duration::from_secs(2),
+            retries: 3,
+            after: AfterUpload::Keep,
+            tags: Vec::new(),
+        }
+    }
+}
+
+fn after_upload(path: &Path, after: &AfterUpload) -> Result<()> {
+    match after {
+        AfterUpload::Keep => Ok(()),
+        AfterUpload::Delete => {
+            std::fs::remove_file(path).map_err(|e| Error::FileError(e.to_string()).into())
+        }
+        AfterUpload::Move(dir) => {
+            let to = dir.join(path.file_name().unwrap());
+            // renaming fails across file systems
+            if std::fs::rename(path, &to).is_err() {
+                std::fs::copy(path, &to)
+                    .and_then(|_| std::fs::remove_file(path))
+                    .map_err(|e| Error::FileError(e.to_string()))?;
+            }
+            Ok(())
+        }
+    }
+}
+
+impl RM {
+    /// Upload the files created or modified in a directory, until an error
+    ///
+    /// A file is put once it went `settle` without writes. Failed uploads
+    /// are retried, and given up on with a warning.
+    pub async fn watch(&self, dir: &Path, dsid: &str, config: &WatchConfig) -> Result<()> {
+        let dir = dir
+            .canonicalize()
+            .map_err(|e| Error::FileError(e.to_string()))?;
+        if let AfterUpload::Move(to) = &config.after {
+            let to = to
+                .canonicalize()
+                .map_err(|e| Error::FileError(e.to_string()))?;
+            if to == dir {
+                Err(anyhow::anyhow!(
+                    "Cannot move uploaded files into the watched directory"
+                ))?;
+            }
+        }
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |res| {
+            let _ = tx.send(res);
+        })
+        .with_context(|| "Failed to create watcher")?;
+        watcher
+            .watch(&dir, RecursiveMode::NonRecursive)
+            .with_context(|| format!("Failed to watch {}", dir.display()))?;
+        tracing::info!("Watching {}", dir.display());
+
+        let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+        let tick = config.settle.min(Duration::from_millis(500));
+        loop {
+            match tokio::time::timeout(tick, rx.recv()).await {
+                Ok(Some(Ok(event))) => {
+                    if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                        for path in event.paths {
+                            pending.insert(path, Instant::now());
+                        }
+                    }
+                }
+                Ok(Some(Err(e))) => tracing::warn!("Watch error: {e}"),
+                Ok(None) => Err(anyhow::anyhow!("Watcher stopped"))?,
+                Err(_) => {}
+            }
+            let settled = pending
+                .iter()
+                .filter(|(_, at)| at.elapsed() >= config.settle)
+                .map(|(path, _)| path.clone())
+                .collect::<Vec<_>>();
+            for path in settled {
+                pending.remove(&path);
+                // renamed away or deleted meanwhile
+                if !path.is_file() {
+                    continue;
+                }
+                if let Some(mr) = self.upload(&path, dsid, config).await {
+                    tracing::info!("Uploaded {} as {}", path.display(), mr.gid);
+                    if let Err(e) = after_upload(&path, &config.after) {
+                        tracing::warn!("Failed to clean up {}: {e:#}", path.display());
+                    }
+                }
+            }
+        }
+    }
+
+    async fn upload(&self, path: &Path, dsid: &str, config: &WatchConfig) -> Option<MetaRecord> {
+        let mut delay = Duration::from_secs(1);
+        for attempt in 0..=config.retries {
+            match self.put(Some(dsid), path, "raw", &config.tags, None).await {
+                Ok(mr) => return Some(mr),
+                // a name taken by another file stays taken
+                Err(e)
+                    if attempt < config.retries
+                        && !matches!(e.downcast_ref::<Error>(), Some(Error::InUse(_))) =>
+                {
+                    tracing::warn!(
+                        "Failed to upload {}, retrying in {delay:?}: {e:#}",
+                        path.display()
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(e) => tracing::warn!("Giving up on {}: {e:#}", path.display()),
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,69 @@
+//! Round trips through backends served locally, configured by environment
+//! variables, e.g. `EASY_FM_SFTP_HOST=localhost ... cargo test -- --ignored`
+
+use std::path::Path;
+
+use anyhow::Result;
+use easy_fm::prelude::*;
+
+fn var(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+async fn roundtrip(r#type: &str, config: serde_json::Value) -> Result<()> {
+    let ds = build(r#type, &config.to_string(), &|x| {
+        Err(anyhow::anyhow!("Datastore {x} not found"))
+    })?;
+    for check in ds.health_check().await {
+        assert!(check.error.is_none(), "{check:?}");
+    }
+
+    let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+    std::fs::create_dir_all(&dir)?;
+    let src = dir.join("src");
+    let data = (0..3 << 20).map(|x| x as u8).collect::<Vec<_>>();
+    std::fs::write(&src, &data)?;
+    let name = format!("easy-fm-test/{} a.bin", uuid::Uuid::new_v4());
+
+    ds.put(name.clone(), &src).await?;
+    assert!(ds.list(Some("easy-fm-test/")).await?.contains(&name));
+    let dst = dir.join("dst");
+    ds.get(name.clone(), Some(&dst)).await?;
+    assert_eq!(std::fs::read(&dst)?, data);
+    ds.del(name.clone()).await?;
+    // deleting an object that is gone succeeds, as with S3
+    ds.del(name.clone()).await?;
+    assert!(!ds.list(Some("easy-fm-test/")).await?.contains(&name));
+
+    // names must not escape the storage
+    assert!(ds.put("../escaped".to_string(), &src).await.is_err());
+    assert!(ds.get(name, Some(Path::new(&dst))).await.is_err());
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "needs an SSH server, see EASY_FM_SFTP_*"]
+async fn sftp() -> Result<()> {
+    let config = serde_json::json!({
+        "host": var("EASY_FM_SFTP_HOST").unwrap_or("localhost".to_string()),
+        "port": var("EASY_FM_SFTP_PORT").map_or(Ok(22), |x| x.parse())?,
+        "user": var("EASY_FM_SFTP_USER").expect("EASY_FM_SFTP_USER is not set"),
+        "key": var("EASY_FM_SFTP_KEY"),
+        "password": var("EASY_FM_SFTP_PASSWORD"),
+        "dir": var("EASY_FM_SFTP_DIR").unwrap_or("/tmp/easy-fm".to_string()),
+        "fingerprint": var("EASY_FM_SFTP_FINGERPRINT"),
+    });
+    roundtrip("sftp", config).await
+}
+
+#[tokio::test]
+#[ignore = "needs a WebDAV server, see EASY_FM_WEBDAV_*"]
+async fn webdav() -> Result<()> {
+    let config = serde_json::json!({
+        "url": var("EASY_FM_WEBDAV_URL").expect("EASY_FM_WEBDAV_URL is not set"),
+        "user": var("EASY_FM_WEBDAV_USER"),
+        "password": var("EASY_FM_WEBDAV_PASSWORD"),
+    });
+    roundtrip("webdav", config).await
+}